@@ -0,0 +1,206 @@
+; Two player pong. Keys 1 / 2 move the left paddle, 9 / 0 the right one.
+; Uses gte, so assemble and run it with --comparisons:
+;   chip8 asm roms/pong.asm -o pong.ch8 --comparisons
+;   chip8 run pong.ch8 --comparisons
+
+alias left_y v0
+alias right_y v1
+alias ball_x v2
+alias ball_y v3
+alias ball_dx v4        ; 0 is right, 1 is left
+alias input v5
+alias collided v6       ; 1 if the ball hit something last frame
+alias ball_dy v7        ; 0 is up, 1 is down
+alias left_score v8
+alias right_score v9
+alias tmp v10           ; v10 - v14 are general purpose
+alias tmp2 v11
+alias speed v14
+alias flag v15
+
+LEFT_DOWN equ 1
+LEFT_UP equ 2
+RIGHT_DOWN equ 9
+RIGHT_UP equ 0
+
+LEFT_PADDLE_X equ 1
+RIGHT_PADDLE_X equ 62
+PADDLE_HEIGHT equ 5
+RIGHT_EDGE equ 63
+BOTTOM_EDGE equ 31
+
+; Move a paddle a pixel down or up while its key is held
+macro paddle_down key, paddle
+    ld tmp, key
+    sknp tmp
+    add paddle, 1
+endm
+
+macro paddle_up key, paddle
+    ld tmp, key
+    ld tmp2, 1
+    sknp tmp
+    sub paddle, tmp2
+endm
+
+jp .start
+
+.ball
+    sprite #
+.paddle
+    sprite # # # # #
+
+.start
+    ld left_y, 16
+    ld right_y, 16
+
+    ld ball_x, 0
+    ld ball_y, 0
+    ld ball_dx, 0
+    ld ball_dy, 0
+
+    ld input, 0
+    ld collided, 0
+
+    ld left_score, 0
+    ld right_score, 0
+
+    call .reset_ball_left
+
+    .game_loop
+        call .main_loop
+        jp .game_loop
+
+    .end
+    jp .end
+
+.main_loop
+    call .update_paddles
+    call .update_ball
+    call .check_for_win
+    call .draw_sprites
+    ret
+
+.draw_score
+    ldsprt left_score
+    ld tmp, 16
+    ld tmp2, 0
+    drw tmp, tmp2, 5
+
+    ldsprt right_score
+    ld tmp, 42
+    drw tmp, tmp2, 5
+    ret
+
+
+.check_for_win
+    sne ball_x, 0
+    call .right_side_point
+
+    sne ball_x, RIGHT_EDGE
+    call .left_side_point
+    ret
+
+    .right_side_point
+    call .reset_ball_left
+    add right_score, 1
+    ret
+
+    .left_side_point
+    call .reset_ball_right
+    add left_score, 1
+    ret
+
+.draw_sprites
+    cls
+    ld flag, 0
+
+    ldi .paddle
+    ld tmp, RIGHT_PADDLE_X
+    drw tmp, right_y, PADDLE_HEIGHT
+    ld tmp, LEFT_PADDLE_X
+    drw tmp, left_y, PADDLE_HEIGHT
+    ldi .ball
+    drw ball_x, ball_y, 1
+    ld collided, flag
+
+    call .draw_score
+
+    ret
+
+.update_paddles
+    paddle_down LEFT_DOWN, left_y
+    paddle_up LEFT_UP, left_y
+    paddle_down RIGHT_DOWN, right_y
+    paddle_up RIGHT_UP, right_y
+    ret
+
+.update_ball
+
+    ld speed, 1
+
+    sne collided, 1
+    call .switch_ball_x_direction
+
+    sne ball_dx, 0
+    add ball_x, speed
+
+    sne ball_dx, 1
+    sub ball_x, speed
+
+    ld tmp, 0
+    sne ball_y, tmp
+    call .switch_ball_y_direction
+
+    ld tmp, BOTTOM_EDGE
+    gte ball_y, tmp
+    sne flag, 1
+    call .switch_ball_y_direction
+
+
+    sne ball_dy, 0
+    add ball_y, speed
+
+    sne ball_dy, 1
+    sub ball_y, speed
+
+    ret
+
+
+.reset_ball_left
+
+    rnd tmp, BOTTOM_EDGE
+    ld ball_x, 8
+    ld ball_y, tmp
+    ld ball_dx, 0
+    ret
+
+
+.reset_ball_right
+    rnd tmp, BOTTOM_EDGE
+    ld ball_x, 47
+    ld ball_y, tmp
+    ld ball_dx, 1
+    ret
+
+.switch_ball_x_direction
+    sne ball_dx, 0
+    jp .switch_ball_0_to_1
+
+    ld ball_dx, 0
+    ret
+
+    .switch_ball_0_to_1
+        ld ball_dx, 1
+        ret
+
+.switch_ball_y_direction
+    sne ball_dy, 0
+    jp .switch_ball_y_0_to_1
+
+    ld ball_dy, 0
+    ret
+
+    .switch_ball_y_0_to_1
+        ld ball_dy, 1
+        ret
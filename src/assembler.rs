@@ -2,426 +2,1167 @@
 
     cls                             - clear screen
     ret                             - return
+    sys <nnn | .label>              - machine code routine, ignored by most
+                                      interpreters
 
     jp <nnn | .label>               - jump to address
+    jp v0, <nnn | .label>           - jump to address + v0
     call <nnn | .label>             - call subrouting
     se vx, <vy | nn>                - skip if equal
     sne vx, <vy | nn>               - skip if not equal
 
-    gt vx, vy                       - vf = vx > vy
-    gte vx, vy                      - vf = vx >= vy
-    lt vx, vy                       - vf = vx < vy
-    lte vx, vy                      - vf = vx <= vy
+    gt vx, vy                       - vf = vx > vy      (comparison extension)
+    gte vx, vy                      - vf = vx >= vy     (comparison extension)
+    lt vx, vy                       - vf = vx < vy      (comparison extension)
+    lte vx, vy                      - vf = vx <= vy     (comparison extension)
 
     ld vx, <nn | vy | dt>           - load nn, vy, or dt into vx
-    ldi, nnn                        - load addr into I
+    ldi <nnn | .label>              - load addr into I      (also ld i, addr)
     ld dt, vx                       - load vx into dt
     ld st, vx                       - load vx into st
     ldsprt vx                       - load sprite version of vx into I
+                                                            (also ld f, vx)
     ldbcd vx                        - load bcd version of vx into mem
+                                                            (also ld b, vx)
     dumpreg vx                      - load registers v0-vx into memory
+                                                            (also ld [i], vx)
     ldreg   vx                      - load registers v0-vx from memory
+                                                            (also ld vx, [i])
     getkey vx                       - stores key press val in vx
+                                                            (also ld vx, k)
 
     add vx, <nn | vy>               - add vx to whatever and save in vx
-    addi vx                         - i += vx
+    addi vx                         - i += vx               (also add i, vx)
     sub vx, vy                      - vx -= vy
     subn vx, vy                     - vx = vy - vx
-    shr vx                          - vx >> 1
-    shl vx                          - vx << 1
+    or vx, vy                       - vx |= vy
+    and vx, vy                      - vx &= vy
+    xor vx, vy                      - vx ^= vy
+    shr vx [, vy]                   - vx >> 1, or vx = vy >> 1 with VIP quirks
+    shl vx [, vy]                   - vx << 1, or vx = vy << 1 with VIP quirks
 
     rnd vx, nn                      - random number in vx
     drw vx, vy, n                   - n-byte sprite drawn from mem i to vx, vy
+                                      (n up to 15)
     skp vx                          - if key with value vx is pressed, skip next instruction
     sknp vx                         - if key with value vx is not pressed, skip next
+
+    db <n | "string">, ...          - raw bytes, strings as ASCII (also .byte)
+    dw <nnnn | .label>, ...         - 16 bit big endian words (also .word)
+    sprite <row> <row> ...          - bitmap rows, # is a set pixel and . a
+                                      clear one. Rows up to 8 wide are one
+                                      byte, up to 16 wide two bytes.
+
+    org <addr>                      - put what follows at addr, 0x200 or above
+    align <n>                       - skip to the next multiple of n
+    reserve <n>                     - leave n bytes free (also ds)
+
+    NAME equ <value>                - constant, usable anywhere a number is
+    define NAME <value>             - same as equ
+    alias name vx                   - another name for a register, from this
+                                      line on
+
+    macro name [param, ...]         - lines up to endm are pasted in wherever
+    endm                              `name arg, ...` is written, with each
+                                      param replaced by its argument
+
+    A label can be used anywhere a number can and stands for the address it
+    was defined at, so labels in front of data point at the data. Values are
+    checked against the field they go in, 12 bits for addresses. Strings take
+    the escapes \n, \t, \0, \\ and \".
+
+    Numbers can be decimal (42), hex (0x2A or $2A), binary (0b101010 or
+    %101010) or a character in single quotes ('*', '\'' and '\\' escaped).
+
+    Any number can also be an expression of numbers, labels and constants
+    with + - * / % << >> & | ^, unary - and ~, and parentheses, binding as
+    in C. low(x) and high(x) are the low and high bytes of x, so
+    `db high(.table), low(.table)` stores an address. Write modulo with a
+    space after the %, since %1 is a binary number. Results that don't fit
+    their field are errors; bytes and words take negatives as two's
+    complement.
+
+    The comparison instructions assemble to 9xy1 - 9xy4, which are not part of
+    CHIP-8 and only run on this emulator with the extension turned on. They are
+    rejected unless Options::comparisons is set.
+
+    Programs start at 0x200. Space skipped by org, align and reserve is filled
+    with zeros, except at the very end where it's left out of the rom. Lines
+    placed on top of each other are errors, and assemble_with_map also returns
+    a MemoryMap of where the code, data and reserved space ended up.
+
+    Macros have to be defined before they're used. Labels defined inside one
+    are renamed for every expansion, so a macro can have its own loop. Macros
+    can use other macros, up to 16 deep. Errors inside an expansion point at
+    the call, with notes for the line in the macro.
+
+    Errors don't stop assembly. Everything wrong with the source is reported
+    at once as a list of Diagnostics, each pointing at a line and column.
 */
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
+
+
+#[derive(Clone, Default)]
+pub struct Options {
+    // Allow gt, gte, lt and lte (non-standard 9xy1 - 9xy4)
+    pub comparisons: bool,
+}
+
+// A problem with the source. Displays like a compiler error, with the
+// offending line and a caret under the column.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    // The source line the error is on
+    pub snippet: String,
+    // Other places involved, like the inside of the macro an error came from
+    pub notes: Vec<Note>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Note {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub snippet: String,
+}
+
+// `kind: message`, then the line with a caret under the column
+fn show(f: &mut fmt::Formatter, kind: &str, message: &str, file: &str, line: usize, column: usize, snippet: &str) -> fmt::Result {
+    let number = line.to_string();
+    let pad = " ".repeat(number.len());
+    // Keep tabs so the caret lines up however they are displayed
+    let indent: String = snippet.chars().take(column - 1)
+        .map(|c| if c == '\t' {'\t'} else {' '})
+        .collect();
+
+    writeln!(f, "{}: {}", kind, message)?;
+    writeln!(f, "{}--> {}:{}:{}", pad, file, line, column)?;
+    writeln!(f, "{} |", pad)?;
+    writeln!(f, "{} | {}", number, snippet)?;
+    write!(f, "{} | {}^", pad, indent)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        show(f, "error", &self.message, &self.file, self.line, self.column, &self.snippet)?;
+        for note in &self.notes {
+            writeln!(f)?;
+            show(f, "note", &note.message, &self.file, note.line, note.column, &note.snippet)?;
+        }
+        Ok(())
+    }
+}
+
+// Where everything in the program went, from assemble_with_map
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+// A run of consecutive lines of the same kind
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub start: u16,
+    // One past the last byte
+    pub end: u16,
+    pub kind: RegionKind,
+    // Labels pointing into the region, in address order
+    pub labels: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionKind {
+    Code,
+    Data,
+    Reserved,
+}
+
+impl MemoryMap {
+    // Last byte in the rom, reserved space at the end isn't written out
+    fn rom_end(&self) -> u16 {
+        self.regions.iter()
+            .filter(|region| region.kind != RegionKind::Reserved)
+            .map(|region| region.end)
+            .max()
+            .unwrap_or(START)
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in &self.regions {
+            let kind = match region.kind {
+                RegionKind::Code => "code",
+                RegionKind::Data => "data",
+                RegionKind::Reserved => "reserved",
+            };
+            writeln!(f, "{:03X} - {:03X}  {:>5} bytes  {:<8}  {}",
+                region.start, region.end - 1, region.end - region.start, kind, region.labels.join(" "))?;
+        }
+
+        let used: u16 = self.regions.iter().map(|region| region.end - region.start).sum();
+        write!(f, "rom is {} bytes, {} of {} bytes of memory are free",
+            self.rom_end() - START, MEMORY_END - START - used, MEMORY_END - START)
+    }
+}
+
+// Programs are loaded here, and can't go past the end of the 4K of memory
+const START: u16 = 0x200;
+const MEMORY_END: u16 = 0x1000;
+
+// A word of source and where it starts, 1 based
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // For tokens from a macro body, the calls that expanded it, innermost
+    // first. Errors are reported at the outermost one.
+    expanded_from: Vec<Token>,
+}
+
+impl Token {
+    fn new(text: String, line: usize, column: usize) -> Token {
+        Token { text, line, column, expanded_from: vec![] }
+    }
+}
+
+// Names operands can refer to. Labels are addresses, constants the
+// expression they were defined as.
+struct Symbols {
+    labels: HashMap<String, u32>,
+    constants: HashMap<String, Vec<Token>>,
+}
+
+// Constants defined in terms of other constants are followed this deep
+// before giving up on a cycle
+const MAX_CONSTANT_DEPTH: usize = 32;
+
+// File and snippets are filled in by assemble, which has the source
+fn error(token: &Token, message: String) -> Diagnostic {
+    // Walk out through the macro calls, noting where in each body it was.
+    // A macro calling itself only gets one note.
+    let mut notes: Vec<Note> = vec![];
+    let mut at = token;
+    for call in &token.expanded_from {
+        let message = format!("in macro {}", call.text);
+        let repeated = notes.last().is_some_and(|last| last.line == at.line && last.column == at.column && last.message == message);
+        if !repeated {
+            notes.push(Note { line: at.line, column: at.column, message, snippet: String::new() });
+        }
+        at = call;
+    }
+
+    Diagnostic {
+        file: String::new(),
+        line: at.line,
+        column: at.column,
+        message,
+        snippet: String::new(),
+        notes,
+    }
+}
+
+// `file` is only used to label diagnostics
+pub fn assemble(input: &str, file: &str, options: &Options) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    assemble_with_map(input, file, options).map(|(rom, _)| rom)
+}
 
+// Assemble and also say where each part of the program ended up
+pub fn assemble_with_map(input: &str, file: &str, options: &Options) -> Result<(Vec<u8>, MemoryMap), Vec<Diagnostic>> {
+    let mut errors: Vec<Diagnostic> = vec![];
+
+    let ins = get_instructions(input, &mut errors);
+    let lines = expand_macros(split_lines(ins), &mut errors);
+    let mut symbols = Symbols { labels: HashMap::new(), constants: HashMap::new() };
+    let dirs = separate_into_directions(lines, &mut symbols, options, &mut errors);
+    check_overlaps(&dirs, &mut errors);
+    let map = memory_map(&dirs, &symbols);
+    let rom = translate(dirs, symbols, map.rom_end(), &mut errors);
+
+    if errors.is_empty() {
+        return Ok((rom, map));
+    }
 
-pub fn assemble(input: &str) -> Vec<u8> {
-    let ins = get_instructions(input);
-    let mut label_table: HashMap<String, u16> = HashMap::new();
-    let dirs = separate_into_directions(ins, &mut label_table);
-    translate(dirs, label_table)
+    let lines: Vec<&str> = input.lines().collect();
+    for err in errors.iter_mut() {
+        err.file = file.to_string();
+        err.snippet = lines.get(err.line - 1).unwrap_or(&"").to_string();
+        for note in err.notes.iter_mut() {
+            note.snippet = lines.get(note.line - 1).unwrap_or(&"").to_string();
+        }
+    }
+    errors.sort_by_key(|err| (err.line, err.column));
+    Err(errors)
 }
 
 fn is_label(s: &str) -> bool {
-    if s.is_empty() || s.chars().nth(0).unwrap() != '.' {false} else {true}
+    s.starts_with('.')
+}
+
+fn is_string(s: &str) -> bool {
+    s.starts_with('"')
+}
+
+// A sprite row token, only produced after `sprite`
+fn is_row(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c == '#' || c == '.')
+}
+
+// Names that start with a dot like labels but aren't
+fn is_directive(s: &str) -> bool {
+    s == ".byte" || s == ".word"
 }
 
 fn is_number(s: &str) -> bool {
-    if s.is_empty() || !char::is_numeric(s.chars().nth(0).unwrap()) {false} else {true}
+    match s.chars().next() {
+        Some(c) => char::is_numeric(c) || c == '$' || c == '%' || c == '\'',
+        None => false,
+    }
+}
+
+// Value of a number token in any of the supported bases, None if it's malformed
+fn parse_number(s: &str) -> Option<u32> {
+    let lower = s.to_lowercase();
+
+    if let Some(hex) = lower.strip_prefix("0x").or(lower.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b").or(lower.strip_prefix('%')) {
+        u32::from_str_radix(bin, 2).ok()
+    } else if let Some(quoted) = s.strip_prefix('\'') {
+        let mut chars = quoted.strip_suffix('\'')?.chars();
+        let ch = match chars.next()? {
+            '\\' => chars.next()?,
+            ch => ch,
+        };
+        if chars.next().is_some() {return None;}
+        Some(ch as u32)
+    } else {
+        s.parse().ok()
+    }
 }
 
 fn is_register(s: &str) -> bool {
-    if s.is_empty() {return false;}
-    let c = s.chars().nth(0).unwrap();
-    if c == 'v' {return true;}
-    if s == "dt" || s == "st" {return true;}
-    return false;
+    if s.len() < 2 {return false;}
+    s.starts_with('v') && s[1..].chars().all(|c| c.is_ascii_digit())
 }
 
-fn get_register_num(s: &str) -> u8 {
-    let num: u8 = s[1..].parse().expect("Could not parse register number.");
-    if num >= 16 {panic!("Register number must be between 0 and 16")}
-    num
+// Operands of ld and add that name something other than a v register
+fn is_special(s: &str) -> bool {
+    matches!(s, "i" | "[i]" | "dt" | "st" | "k" | "f" | "b")
 }
 
-fn translate(input: Vec<Vec<String>>, labels: HashMap<String, u16>) -> Vec<u8> {
-    let mut ins: Vec<u8> = vec![];
+// A constant or alias name
+fn is_name(s: &str) -> bool {
+    match s.chars().next() {
+        Some(c) => (c.is_alphabetic() || c == '_') && !is_register(s) && !is_special(s),
+        None => false,
+    }
+}
 
-    for line in input {
-        match line[0].as_str() {
-            "cls" => ins.append(&mut vec![0x00, 0xE0]),
-            "ret" => ins.append(&mut vec![0x00, 0xEE]),
-            "jp" => {
-                let mut location: u16;
-                if is_label(&line[1]) {
-                    if !labels.contains_key(&line[1]) {panic!("No label {}", line[1]);}
-                    location = labels[&line[1]] + 0x200;
-                } else {location = line[1].parse().expect("Could not parse number into 12 bits.")}
-                location &= 0xFFF;
-
-                ins.append(&mut vec![(0x01 << 4) | (location >> 8) as u8, (location & 0xFF) as u8])
+fn get_register_num(token: &Token) -> Result<u8, Diagnostic> {
+    match token.text[1..].parse::<u8>() {
+        Ok(num) if num < 16 => Ok(num),
+        Ok(_) => Err(error(token, "Register number must be between 0 and 15.".to_string())),
+        Err(_) => Err(error(token, format!("Could not parse register number in {}.", token.text))),
+    }
+}
+
+// Bytes of a quoted string token, None if it's unterminated or has a bad
+// escape or non-ASCII character
+fn parse_string(s: &str) -> Option<Vec<u8>> {
+    let mut chars = s.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut bytes = vec![];
+
+    while let Some(ch) = chars.next() {
+        let ch = match ch {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                _ => return None,
             },
-            "call" => {
-                let mut location: u16;
-                if is_label(&line[1]) {
-                    if !labels.contains_key(&line[1]) {panic!("No label {}", line[1]);}
-                    location = labels[&line[1]] + 0x200;
-                } else {location = line[1].parse().expect("Could not parse number into 12 bits.")}
-                location &= 0xFFF;
-                ins.append(&mut vec![(0x02 << 4) | (location >> 8) as u8, (location & 0xFF) as u8]);
-            }
-            "se" => {
-                if is_register(&line[2]) {
-                    let x_reg = get_register_num(&line[1]);
-                    let y_reg = get_register_num(&line[2]);
-                    ins.append(&mut vec![(0x5 << 4) | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x0]);
-                } else {
-                    let x_reg = get_register_num(&line[1]);
-                    let nn: u8 = line[2].parse().unwrap();
-                    ins.append(&mut vec![(0x3 << 4) | (x_reg & 0xF), nn]);
-                }
+            ch => ch,
+        };
+        if !ch.is_ascii() {return None;}
+        bytes.push(ch as u8);
+    }
+
+    Some(bytes)
+}
+
+fn get_string(token: &Token) -> Result<Vec<u8>, Diagnostic> {
+    if token.text.len() < 2 || !token.text.ends_with('"') {
+        return Err(error(token, "Unterminated string.".to_string()));
+    }
+    parse_string(&token.text).ok_or_else(|| error(token, format!("Invalid string {}.", token.text)))
+}
+
+// A sprite row as one byte, or two for rows wider than 8 pixels. Pixels
+// fill from the left.
+fn get_row(token: &Token) -> Result<Vec<u8>, Diagnostic> {
+    let width = token.text.len();
+    if width > 16 {
+        return Err(error(token, format!("Sprite rows can be at most 16 pixels wide, this one is {}.", width)));
+    }
+
+    let bits = token.text.chars().fold(0u16, |bits, c| (bits << 1) | (c == '#') as u16);
+    if width > 8 {
+        Ok((bits << (16 - width)).to_be_bytes().to_vec())
+    } else {
+        Ok(vec![(bits << (8 - width)) as u8])
+    }
+}
+
+// Binary operators, loosest binding first
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+// Recursive descent over the tokens of an expression. Without symbols it
+// only checks the syntax and every name counts as 0, which is how operands
+// are picked out before all the labels are known.
+struct Expression<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    symbols: Option<&'a Symbols>,
+    // How many constants deep this is
+    depth: usize,
+}
+
+impl<'a> Expression<'a> {
+    fn next(&mut self) -> Result<&'a Token, Diagnostic> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token)
             },
-            "sne" => {
-                if is_register(&line[2]) {
-                    let x_reg = get_register_num(&line[1]);
-                    let y_reg = get_register_num(&line[2]);
-                    ins.append(&mut vec![(0x9 << 4) | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x0]);
-                } else {
-                    let x_reg = get_register_num(&line[1]);
-                    let nn: u8 = line[2].parse().unwrap();
-                    ins.append(&mut vec![(0x4 << 4) | (x_reg & 0xF), nn]);
-                }
+            None => {
+                let last = &self.tokens[self.pos - 1];
+                let end = Token { text: String::new(), column: last.column + last.text.chars().count(), ..last.clone() };
+                Err(error(&end, format!("Expected a value after {}.", last.text)))
             },
-            "gt" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
-                ins.append(&mut vec![0x90 | (x_reg & 0xF), (y_reg << 4) | 0x1]);
+        }
+    }
+
+    fn close(&mut self, open: &Token) -> Result<(), Diagnostic> {
+        match self.tokens.get(self.pos) {
+            Some(token) if token.text == ")" => {
+                self.pos += 1;
+                Ok(())
             },
-            "gte" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
-                ins.append(&mut vec![0x90 | (x_reg & 0xF), (y_reg << 4) | 0x2]);
+            _ => Err(error(open, "This ( is never closed.".to_string())),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, Diagnostic> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.tokens.get(self.pos).filter(|op| PRECEDENCE[level].contains(&op.text.as_str())) {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            if self.symbols.is_some() {
+                left = apply(op, left, right)?;
+            }
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, Diagnostic> {
+        let token = self.next()?;
+        let followed_by_paren = self.tokens.get(self.pos).is_some_and(|next| next.text == "(");
+
+        match token.text.as_str() {
+            "-" => {
+                let value = self.unary()?;
+                value.checked_neg().ok_or_else(|| error(token, "Expression overflows.".to_string()))
             },
-            "lt" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
-                ins.append(&mut vec![0x90 | (x_reg & 0xF), (y_reg << 4) | 0x3]);
+            "~" => Ok(!self.unary()?),
+            "(" => {
+                let value = self.binary(0)?;
+                self.close(token)?;
+                Ok(value)
             },
-            "lte" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
-                ins.append(&mut vec![0x90 | (x_reg & 0xF), (y_reg << 4) | 0x4]);
+            // Bytes of a 16 bit value, mostly for splitting addresses
+            "low" | "high" if followed_by_paren => {
+                let open = self.next()?;
+                let value = self.binary(0)?;
+                self.close(open)?;
+                Ok(if token.text == "low" {value & 0xFF} else {(value >> 8) & 0xFF})
             },
-            "ld" => {
-                if is_register(&line[1]) {
-                    let x_reg = get_register_num(&line[1]);
-
-                    if is_number(&line[2]) {
-                        let num: u8 = line[2].parse().unwrap();
-                        ins.append(&mut vec![(0x6 << 4) | (x_reg & 0xF), num]);
-
-                    } else if is_register(&line[2]) {
-                        let y_reg = get_register_num(&line[2]);
-                        ins.append(&mut vec![(0x08 << 4) | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x0]);
-                    } else {
-                        ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x07]);
-                    }
+            _ => self.value(token),
+        }
+    }
+
+    fn value(&self, token: &Token) -> Result<i64, Diagnostic> {
+        if is_number(&token.text) {
+            return match parse_number(&token.text) {
+                Some(n) => Ok(n as i64),
+                None => Err(error(token, format!("Invalid number {}.", token.text))),
+            };
+        }
+        if !is_label(&token.text) && !is_name(&token.text) {
+            return Err(error(token, format!("Expected a value, got {}.", token.text)));
+        }
+
+        let symbols = match self.symbols {
+            Some(symbols) => symbols,
+            None => return Ok(0),
+        };
+
+        if is_label(&token.text) {
+            return match symbols.labels.get(&token.text) {
+                Some(address) => Ok(*address as i64),
+                None => Err(error(token, format!("No label {}", token.text))),
+            };
+        }
+
+        if self.depth > MAX_CONSTANT_DEPTH {
+            return Err(error(token, format!("Constant {} is defined in terms of itself.", token.text)));
+        }
+        match symbols.constants.get(&token.text) {
+            // Problems inside the definition are reported where it's used
+            Some(value) => evaluate(value, Some(symbols), self.depth + 1).map_err(|err| error(token, err.message)),
+            None => Err(error(token, format!("Unknown constant {}", token.text))),
+        }
+    }
+}
+
+fn apply(op: &Token, left: i64, right: i64) -> Result<i64, Diagnostic> {
+    let result = match op.text.as_str() {
+        "+" => left.checked_add(right),
+        "-" => left.checked_sub(right),
+        "*" => left.checked_mul(right),
+        "/" | "%" if right == 0 => return Err(error(op, "Division by zero.".to_string())),
+        "/" => left.checked_div(right),
+        "%" => left.checked_rem(right),
+        "<<" | ">>" if !(0..64).contains(&right) => {
+            return Err(error(op, format!("Can't shift by {}.", right)));
+        },
+        "<<" => left.checked_shl(right as u32),
+        ">>" => left.checked_shr(right as u32),
+        "&" => Some(left & right),
+        "|" => Some(left | right),
+        _ => Some(left ^ right),
+    };
+
+    result.ok_or_else(|| error(op, "Expression overflows.".to_string()))
+}
+
+// Value of a whole expression
+fn evaluate(tokens: &[Token], symbols: Option<&Symbols>, depth: usize) -> Result<i64, Diagnostic> {
+    let mut expression = Expression { tokens, pos: 0, symbols, depth };
+    let value = expression.binary(0)?;
+    match tokens.get(expression.pos) {
+        Some(extra) => Err(error(extra, format!("Unexpected {} in expression.", extra.text))),
+        None => Ok(value),
+    }
+}
+
+// How many tokens from the start of `tokens` make up one expression
+fn expression_length(tokens: &[Token]) -> Result<usize, Diagnostic> {
+    let mut expression = Expression { tokens, pos: 0, symbols: None, depth: 0 };
+    expression.binary(0)?;
+    Ok(expression.pos)
+}
+
+// Check a value fits an instruction field of `max` and say what it was if
+// not. Byte and word fields also take negative numbers, as two's complement.
+fn in_range(op: &[Token], value: i64, max: i64) -> Result<u32, Diagnostic> {
+    let min = if max == 0xFFF {0} else {-(max + 1) / 2};
+    if (min..=max).contains(&value) {
+        return Ok((value & max) as u32);
+    }
+
+    let got = if op.len() == 1 && is_number(&op[0].text) {
+        op[0].text.clone()
+    } else {
+        let text: Vec<&str> = op.iter().map(|token| token.text.as_str()).collect();
+        if value < 0 {format!("{} ({})", text.join(" "), value)} else {format!("{} (0x{:X})", text.join(" "), value)}
+    };
+    let message = if max == 0xFFF {
+        format!("Address must be between 0 and 0xFFF, got {}.", got)
+    } else {
+        format!("Expected a number between {} and {}, got {}.", min, max, got)
+    };
+    Err(error(&op[0], message))
+}
+
+fn get_byte(op: &[Token], symbols: &Symbols) -> Result<u8, Diagnostic> {
+    let n = evaluate(op, Some(symbols), 0)?;
+    Ok(in_range(op, n, 0xFF)? as u8)
+}
+
+// 12 bit addresses, the only ones nnn opcodes can hold
+fn get_address(op: &[Token], symbols: &Symbols) -> Result<u16, Diagnostic> {
+    let n = evaluate(op, Some(symbols), 0)?;
+    Ok(in_range(op, n, 0xFFF)? as u16)
+}
+
+fn get_word(op: &[Token], symbols: &Symbols) -> Result<u16, Diagnostic> {
+    let n = evaluate(op, Some(symbols), 0)?;
+    Ok(in_range(op, n, 0xFFFF)? as u16)
+}
+
+// What an operand is, to check instructions get the right ones
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Register,
+    Special,
+    Value,
+    Text,
+    Row,
+}
+
+fn kind(op: &[Token]) -> Kind {
+    match op[0].text.as_str() {
+        s if op.len() == 1 && is_register(s) => Kind::Register,
+        s if op.len() == 1 && is_special(s) => Kind::Special,
+        s if op.len() == 1 && is_string(s) => Kind::Text,
+        s if op.len() == 1 && is_row(s) => Kind::Row,
+        _ => Kind::Value,
+    }
+}
+
+fn is_register_op(op: &[Token]) -> bool {
+    kind(op) == Kind::Register
+}
+
+// An instruction or directive and its operands. Each operand is a single
+// register, string or sprite row token, or the tokens of an expression.
+struct Direction {
+    name: Token,
+    operands: Vec<Vec<Token>>,
+    // Where it goes in memory and how many bytes it takes, set once the
+    // lines before it are laid out
+    address: u32,
+    size: u32,
+}
+
+// Where `line` starts and how many bytes it takes when the previous line
+// ended at `address`. Data sizes are known before labels are resolved, org,
+// align and reserve can only use labels and constants from above them.
+fn layout(line: &Direction, address: u32, symbols: &Symbols) -> Result<(u32, u32), Diagnostic> {
+    let operands = &line.operands;
+    let value = |min: i64, max: i64, what: &str| -> Result<u32, Diagnostic> {
+        let op = &operands[0];
+        let n = evaluate(op, Some(symbols), 0)?;
+        if !(min..=max).contains(&n) {
+            return Err(error(&op[0], format!("{} must be between {} and 0x{:X}, got {}.", what, min, max, n)));
+        }
+        Ok(n as u32)
+    };
+
+    let size = match line.name.text.as_str() {
+        "org" => {
+            let origin = value(0, MEMORY_END as i64, "org")?;
+            if origin < START as u32 {
+                return Err(error(&operands[0][0], format!("org can't go below 0x{:X}, where programs are loaded.", START)));
+            }
+            return Ok((origin, 0));
+        },
+        "align" => {
+            let alignment = value(1, MEMORY_END as i64, "Alignment")?;
+            (alignment - address % alignment) % alignment
+        },
+        "reserve" | "ds" => value(0, MEMORY_END as i64, "Reserved space")?,
+        "db" | ".byte" => operands.iter()
+            .map(|op| if kind(op) == Kind::Text {parse_string(&op[0].text).map_or(0, |s| s.len() as u32)} else {1})
+            .sum(),
+        "dw" | ".word" => 2 * operands.len() as u32,
+        "sprite" => operands.iter().map(|row| if row[0].text.len() > 8 {2} else {1}).sum(),
+        _ => 2,
+    };
+
+    Ok((address, size))
+}
+
+// Report lines that org has placed on top of each other, blaming whichever
+// comes later in the source
+fn check_overlaps(lines: &[Direction], errors: &mut Vec<Diagnostic>) {
+    let mut placed: Vec<&Direction> = lines.iter().filter(|line| line.size > 0).collect();
+    placed.sort_by_key(|line| line.address);
+
+    let mut furthest: Option<&Direction> = None;
+    for line in placed {
+        if let Some(prev) = furthest {
+            let prev_end = prev.address + prev.size;
+            if line.address < prev_end {
+                let (later, earlier) = if line.name.line > prev.name.line {(line, prev)} else {(prev, line)};
+                let end = prev_end.min(line.address + line.size);
+                errors.push(error(&later.name, format!("Bytes 0x{:03X} - 0x{:03X} are already used by line {}.", line.address, end - 1, earlier.name.line)));
+            }
+            if prev_end >= line.address + line.size {
+                continue;
+            }
+        }
+        furthest = Some(line);
+    }
+}
+
+fn memory_map(lines: &[Direction], symbols: &Symbols) -> MemoryMap {
+    let mut placed: Vec<&Direction> = lines.iter().filter(|line| line.size > 0).collect();
+    placed.sort_by_key(|line| line.address);
+
+    let mut regions: Vec<Region> = vec![];
+    for line in placed {
+        let kind = match line.name.text.as_str() {
+            "db" | ".byte" | "dw" | ".word" | "sprite" => RegionKind::Data,
+            "reserve" | "ds" | "align" => RegionKind::Reserved,
+            _ => RegionKind::Code,
+        };
+        let start = line.address.min(MEMORY_END as u32) as u16;
+        let end = (line.address + line.size).min(MEMORY_END as u32) as u16;
+
+        match regions.last_mut() {
+            Some(last) if last.kind == kind && last.end == start => last.end = end,
+            _ => regions.push(Region { start, end, kind, labels: vec![] }),
+        }
+    }
+
+    let mut labels: Vec<(&String, &u32)> = symbols.labels.iter().collect();
+    labels.sort_by_key(|(name, address)| (**address, name.to_string()));
+    for (name, address) in labels {
+        if let Some(region) = regions.iter_mut().find(|region| (region.start as u32..region.end as u32).contains(address)) {
+            region.labels.push(name.clone());
+        }
+    }
+
+    MemoryMap { regions }
+}
 
-                } else if line[1].chars().nth(0).unwrap() == 'd' {
-                    let x_reg = get_register_num(&line[1]);
-                    ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x15]);
-                } else if line[1].chars().nth(0).unwrap() == 's' {
-                    let x_reg = get_register_num(&line[1]);
-                    ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x18]);
+// Write every line into a rom image ending at `end`. Gaps left by org and
+// reserve are zeros.
+fn translate(input: Vec<Direction>, symbols: Symbols, end: u16, errors: &mut Vec<Diagnostic>) -> Vec<u8> {
+    let mut rom: Vec<u8> = vec![0; (end - START) as usize];
+
+    for line in input {
+        match translate_line(&line, &symbols) {
+            Ok(bytes) => {
+                let start = (line.address - START as u32) as usize;
+                if let Some(dest) = rom.get_mut(start..start + bytes.len()) {
+                    dest.copy_from_slice(&bytes);
                 }
             },
-            "ldi" => {
-                let n: u16 = line[1].parse().unwrap();
-                if n >= 4096 {panic!("Address must be between 0 and 4095.")}
-                ins.append(&mut vec![(0xA << 4) | ((n >> 8) as u8 & 0xF), (n & 0xFF) as u8]);
-            },
-            "ldsprt" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x29]);
-            },
-            "ldbcd" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x33]);
-            },
-            "dumpreg" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x55]);
-            },
-            "ldreg" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x65]);
-            },
-            "getkey" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x0A]);
-            },
-            "add" => {
-                let x_reg = get_register_num(&line[1]);
+            Err(err) => errors.push(err),
+        }
+    }
+
+    rom
+}
+
+fn translate_line(line: &Direction, symbols: &Symbols) -> Result<Vec<u8>, Diagnostic> {
+    let ops = &line.operands;
+    let bytes = match line.name.text.as_str() {
+        "cls" => vec![0x00, 0xE0],
+        // Space only, already zero
+        "align" | "reserve" | "ds" => vec![],
+        "sys" => {
+            let location = get_address(&ops[0], symbols)?;
+            vec![(location >> 8) as u8, (location & 0xFF) as u8]
+        },
+        "ret" => vec![0x00, 0xEE],
+        // jp v0, nnn
+        "jp" if ops.len() == 2 => {
+            let location = get_address(&ops[1], symbols)?;
+            vec![(0x0B << 4) | (location >> 8) as u8, (location & 0xFF) as u8]
+        },
+        "jp" => {
+            let location = get_address(&ops[0], symbols)?;
+            vec![(0x01 << 4) | (location >> 8) as u8, (location & 0xFF) as u8]
+        },
+        "call" => {
+            let location = get_address(&ops[0], symbols)?;
+            vec![(0x02 << 4) | (location >> 8) as u8, (location & 0xFF) as u8]
+        }
+        "se" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            if is_register_op(&ops[1]) {
+                let y_reg = get_register_num(&ops[1][0])?;
+                vec![(0x5 << 4) | (x_reg & 0xF), (y_reg << 4) & 0xF0]
+            } else {
+                vec![(0x3 << 4) | (x_reg & 0xF), get_byte(&ops[1], symbols)?]
+            }
+        },
+        "sne" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            if is_register_op(&ops[1]) {
+                let y_reg = get_register_num(&ops[1][0])?;
+                vec![(0x9 << 4) | (x_reg & 0xF), (y_reg << 4) & 0xF0]
+            } else {
+                vec![(0x4 << 4) | (x_reg & 0xF), get_byte(&ops[1], symbols)?]
+            }
+        },
+        "gt" | "gte" | "lt" | "lte" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            let y_reg = get_register_num(&ops[1][0])?;
+            let n = match line.name.text.as_str() {
+                "gt" => 0x1,
+                "gte" => 0x2,
+                "lt" => 0x3,
+                _ => 0x4,
+            };
+            vec![0x90 | (x_reg & 0xF), (y_reg << 4) | n]
+        },
+        "ld" => {
+            let (vx, vy) = (&ops[0][0], &ops[1][0]);
+            match (vx.text.as_str(), vy.text.as_str()) {
+                ("dt", _) => vec![(0xF << 4) | get_register_num(vy)?, 0x15],
+                ("st", _) => vec![(0xF << 4) | get_register_num(vy)?, 0x18],
+                (_, "dt") => vec![(0xF << 4) | get_register_num(vx)?, 0x07],
+                _ if is_register_op(&ops[1]) => {
+                    let x_reg = get_register_num(vx)?;
+                    let y_reg = get_register_num(vy)?;
+                    vec![(0x08 << 4) | (x_reg & 0xF), (y_reg << 4) & 0xF0]
+                },
+                _ => vec![(0x6 << 4) | (get_register_num(vx)? & 0xF), get_byte(&ops[1], symbols)?],
+            }
+        },
+        "ldi" => {
+            let n = get_address(&ops[0], symbols)?;
+            vec![(0xA << 4) | ((n >> 8) as u8 & 0xF), (n & 0xFF) as u8]
+        },
+        "ldsprt" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x29],
+        "ldbcd" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x33],
+        "dumpreg" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x55],
+        "ldreg" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x65],
+        "getkey" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x0A],
+        "add" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+
+            if is_register_op(&ops[1]) {
+                let y_reg = get_register_num(&ops[1][0])?;
+                vec![(8 << 4) | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x4]
+            } else {
+                vec![0x70 | (x_reg & 0xF), get_byte(&ops[1], symbols)?]
+            }
+        },
+        "addi" => vec![(0xF << 4) | get_register_num(&ops[0][0])?, 0x1E],
+        "sub" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            let y_reg = get_register_num(&ops[1][0])?;
+            vec![0x80 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x5]
+        },
+        "subn" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            let y_reg = get_register_num(&ops[1][0])?;
+            vec![0x80 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x7]
+        },
+        "or" | "and" | "xor" | "shr" | "shl" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            let y_reg = match ops.get(1) {
+                Some(vy) => get_register_num(&vy[0])?,
+                None => 0,
+            };
+            let n = match line.name.text.as_str() {
+                "or" => 0x1,
+                "and" => 0x2,
+                "xor" => 0x3,
+                "shr" => 0x6,
+                _ => 0xE,
+            };
+            vec![0x80 | (x_reg & 0xF), (y_reg << 4) | n]
+        },
+        "rnd" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            vec![0xC0 | (x_reg & 0xF), get_byte(&ops[1], symbols)?]
+        },
+        "drw" => {
+            let x_reg = get_register_num(&ops[0][0])?;
+            let y_reg = get_register_num(&ops[1][0])?;
+            let num = get_byte(&ops[2], symbols)?;
+            if num > 15 {return Err(error(&ops[2][0], "Sprites have a maximum length of 15.".to_string()))}
 
-                if is_register(&line[2]) {
-                    let y_reg = get_register_num(&line[2]);
-                    ins.append(&mut vec![(8 << 4) | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x4]);
+            vec![0xD0 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | (num & 0x0F)]
+        },
+        "skp" => vec![(0xE << 4) | get_register_num(&ops[0][0])?, 0x9E],
+        "sknp" => vec![(0xE << 4) | get_register_num(&ops[0][0])?, 0xA1],
+        "db" | ".byte" => {
+            let mut bytes = vec![];
+            for byte in ops {
+                if kind(byte) == Kind::Text {
+                    bytes.append(&mut get_string(&byte[0])?);
                 } else {
-                    let num: u8 = line[2].parse().unwrap();
-                    ins.append(&mut vec![0x70 | (x_reg & 0xF), num]);
+                    bytes.push(get_byte(byte, symbols)?);
                 }
-            },
-            "addi" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xF << 4) | (x_reg & 0xF), 0x1E]);
-            },
-            "sub" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
+            }
+            bytes
+        },
+        "dw" | ".word" => {
+            let mut bytes = vec![];
+            for word in ops {
+                bytes.extend_from_slice(&get_word(word, symbols)?.to_be_bytes());
+            }
+            bytes
+        },
+        "sprite" => {
+            let mut bytes = vec![];
+            for row in ops {
+                bytes.append(&mut get_row(&row[0])?);
+            }
+            bytes
+        },
+        _ => return Err(error(&line.name, format!("Unknown instruction {}", line.name.text))),
+    };
 
-                ins.append(&mut vec![0x80 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x5]);
-            },
-            "subn" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
+    Ok(bytes)
+}
 
-                ins.append(&mut vec![0x80 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | 0x7]);
-            },
-            "shr" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![0x80 | (x_reg & 0xF), 0x06]);
-            },
-            "shl" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![0x80 | (x_reg & 0xF), 0x0E]);
-            },
-            "rnd" => {
-                let x_reg = get_register_num(&line[1]);
-                let num: u8 = line[2].parse().unwrap();
-                ins.append(&mut vec![0xC0 | (x_reg & 0xF), num]);
-            },
-            "drw" => {
-                let x_reg = get_register_num(&line[1]);
-                let y_reg = get_register_num(&line[2]);
-                let num: u8 = line[3].parse().unwrap();
-                if num > 10 {panic!("Sprites have a maximum length of 10.")}
 
-                ins.append(&mut vec![0xD0 | (x_reg & 0xF), ((y_reg << 4) & 0xF0) | (num & 0x0F)]);
-            },
-            "skp" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xE << 4) | (x_reg & 0xF), 0x9E]);
+// Group tokens by source line. Labels at the start of a line get lines of
+// their own.
+fn split_lines(input: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = vec![];
+    let mut starting = true;
+
+    for token in input {
+        let same_line = lines.last().is_some_and(|line| line[0].line == token.line);
+        if !same_line {
+            starting = true;
+        }
+
+        if is_label(&token.text) && !is_directive(&token.text) && starting {
+            lines.push(vec![token]);
+        } else if same_line && !starting {
+            lines.last_mut().unwrap().push(token);
+        } else {
+            starting = false;
+            lines.push(vec![token]);
+        }
+    }
+
+    lines
+}
+
+// A macro's parameter names and the lines between macro and endm
+struct Macro {
+    name: Token,
+    params: Vec<Token>,
+    body: Vec<Vec<Token>>,
+}
+
+// Macros calling macros are expanded this deep before giving up on a cycle
+const MAX_MACRO_DEPTH: usize = 16;
+
+// Take out `macro name a, b ... endm` definitions and replace every call
+// with the body, parameters swapped for the arguments
+fn expand_macros(input: Vec<Vec<Token>>, errors: &mut Vec<Diagnostic>) -> Vec<Vec<Token>> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut out: Vec<Vec<Token>> = vec![];
+    // Numbers each expansion, to give its labels unique names
+    let mut expansions = 0;
+
+    let mut lines = input.into_iter();
+    while let Some(line) = lines.next() {
+        match line[0].text.as_str() {
+            "macro" => {
+                let mut body = vec![];
+                let mut closed = false;
+                for inner in lines.by_ref() {
+                    match inner[0].text.as_str() {
+                        "endm" => {
+                            if let Some(extra) = inner.get(1) {
+                                errors.push(error(extra, format!("Unexpected {} after endm.", extra.text)));
+                            }
+                            closed = true;
+                            break;
+                        },
+                        "macro" => errors.push(error(&inner[0], "Macros can't be defined inside other macros.".to_string())),
+                        _ => body.push(inner),
+                    }
+                }
+                if !closed {
+                    errors.push(error(&line[0], "This macro is never closed with endm.".to_string()));
+                }
+
+                match define_macro(&line, body, &macros) {
+                    Ok(mac) => {macros.insert(mac.name.text.clone(), mac);},
+                    Err(err) => errors.push(err),
+                }
             },
-            "sknp" => {
-                let x_reg = get_register_num(&line[1]);
-                ins.append(&mut vec![(0xE << 4) | (x_reg & 0xF), 0xA1]);
+            "endm" => errors.push(error(&line[0], "endm without a macro.".to_string())),
+            _ => expand(line, &macros, 0, &mut expansions, &mut out, errors),
+        }
+    }
+
+    out
+}
+
+fn define_macro(line: &[Token], body: Vec<Vec<Token>>, macros: &HashMap<String, Macro>) -> Result<Macro, Diagnostic> {
+    let name = match line.get(1) {
+        Some(name) if is_name(&name.text) => name,
+        Some(name) => return Err(error(name, format!("{} can't be used as a macro name.", name.text))),
+        None => return Err(error(&end_of(line), "Expected a name after macro.".to_string())),
+    };
+    if let Some(earlier) = macros.get(&name.text) {
+        let mut err = error(name, format!("Macro {} was already defined.", name.text));
+        err.notes.push(Note { line: earlier.name.line, column: earlier.name.column, message: "first defined here".to_string(), snippet: String::new() });
+        return Err(err);
+    }
+
+    let mut params: Vec<Token> = vec![];
+    for param in line[2..].iter().filter(|token| token.text != ",") {
+        if !is_name(&param.text) {
+            return Err(error(param, format!("{} can't be used as a parameter name.", param.text)));
+        }
+        if params.iter().any(|other| other.text == param.text) {
+            return Err(error(param, format!("Parameter {} appears twice.", param.text)));
+        }
+        params.push(param.clone());
+    }
+
+    Ok(Macro { name: name.clone(), params, body })
+}
+
+// Split macro arguments at the commas outside parentheses
+fn split_arguments(call: &Token, tokens: &[Token]) -> Result<Vec<Vec<Token>>, Diagnostic> {
+    let mut args: Vec<Vec<Token>> = vec![];
+    if tokens.is_empty() {
+        return Ok(args);
+    }
+
+    let mut arg: Vec<Token> = vec![];
+    let mut depth = 0;
+    for token in tokens {
+        match token.text.as_str() {
+            "," if depth == 0 => {
+                if arg.is_empty() {
+                    return Err(error(token, format!("Missing argument for {}.", call.text)));
+                }
+                args.push(arg);
+                arg = vec![];
+                continue;
             },
-            _ => panic!("Unknown instruction {}", line[0])
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            _ => (),
         }
+        arg.push(token.clone());
+    }
+    if arg.is_empty() {
+        return Err(error(&end_of(tokens), format!("Missing argument for {}.", call.text)));
     }
+    args.push(arg);
 
-    ins
+    Ok(args)
 }
 
+// Push `line` to `out`, expanded if it's a macro call
+fn expand(line: Vec<Token>, macros: &HashMap<String, Macro>, depth: usize, expansions: &mut usize, out: &mut Vec<Vec<Token>>, errors: &mut Vec<Diagnostic>) {
+    let mac = match macros.get(&line[0].text) {
+        Some(mac) => mac,
+        None => {
+            out.push(line);
+            return;
+        },
+    };
+    let call = &line[0];
 
-fn separate_into_directions(input: Vec<String>, label_table: &mut HashMap<String, u16>) -> Vec<Vec<String>> {
-    let mut ins: Vec<Vec<String>> = vec![];
-    let mut pos = 0;
-    let mut instruction_num: u16 = 0;
+    if depth == MAX_MACRO_DEPTH {
+        errors.push(error(call, format!("Macros nest more than {} deep here, does {} call itself?", MAX_MACRO_DEPTH, call.text)));
+        return;
+    }
 
-    let advance = |pos: &mut usize, input: &Vec<String>| -> String {
-        *pos += 1;
-        if *pos >= input.len() {panic!("Unexpected end to input stream.")}
-        input[*pos].clone()
+    // Tolerate a comma straight after the name, like instructions do
+    let rest = if line.get(1).is_some_and(|token| token.text == ",") {&line[2..]} else {&line[1..]};
+    let args = match split_arguments(call, rest) {
+        Ok(args) => args,
+        Err(err) => return errors.push(err),
     };
+    if args.len() != mac.params.len() {
+        let mut err = error(call, format!("{} takes {} argument(s), got {}.", call.text, mac.params.len(), args.len()));
+        err.notes.push(Note { line: mac.name.line, column: mac.name.column, message: format!("{} is defined here", call.text), snippet: String::new() });
+        return errors.push(err);
+    }
 
-    while pos < input.len() {
-        if input[pos].chars().nth(0).unwrap() == '.' {
-            if label_table.contains_key(&input[pos]) {panic!("Label {} was already defined.", input[pos]);}
-            label_table.insert(input[pos].clone(), instruction_num);
-            pos += 1;
-        } else {
-            match input[pos].as_str() {
-                "cls" => {
-                    ins.push(vec!["cls".to_string()]);
-                },
-                "ret" => {
-                    ins.push(vec!["ret".to_string()]);
+    *expansions += 1;
+    // Labels defined in the body, renamed so each expansion has its own
+    let locals: Vec<&String> = mac.body.iter()
+        .filter(|body_line| body_line.len() == 1 && is_label(&body_line[0].text) && !is_directive(&body_line[0].text))
+        .map(|body_line| &body_line[0].text)
+        .collect();
+
+    let mut site = call.clone();
+    site.expanded_from = vec![];
+    let mut expanded_from = vec![site];
+    expanded_from.extend(call.expanded_from.iter().cloned());
+
+    for body_line in &mac.body {
+        let mut new_line: Vec<Token> = vec![];
+        for token in body_line {
+            match mac.params.iter().position(|param| param.text == token.text) {
+                // Arguments keep their own place, at the call
+                Some(i) => new_line.extend(args[i].iter().cloned()),
+                None => {
+                    let mut token = token.clone();
+                    token.expanded_from = expanded_from.clone();
+                    if locals.contains(&&token.text) {
+                        token.text = format!("{}@{}", token.text, expansions);
+                    }
+                    new_line.push(token);
                 },
-                "call" => {
-                    let next = advance(&mut pos, &input);
-                    if !is_number(&next) && !is_label(&next) {panic!("Expected number of label after call.")}
-                    ins.push(vec!["call".to_string(), next]);
+            }
+        }
+        expand(new_line, macros, depth + 1, expansions, out, errors);
+    }
+}
+
+fn separate_into_directions(input: Vec<Vec<Token>>, symbols: &mut Symbols, options: &Options, errors: &mut Vec<Diagnostic>) -> Vec<Direction> {
+    let mut ins: Vec<Direction> = vec![];
+    let mut address: u32 = START as u32;
+    // Only the first line past the end of memory is reported
+    let mut overflowed = false;
+
+    // Register names from `alias`, swapped in for operands that follow
+    let mut aliases: HashMap<String, String> = HashMap::new();
+
+    for line in &input {
+        if is_label(&line[0].text) && !is_directive(&line[0].text) {
+            if symbols.labels.contains_key(&line[0].text) {
+                errors.push(error(&line[0], format!("Label {} was already defined.", line[0].text)));
+            } else {
+                symbols.labels.insert(line[0].text.clone(), address);
+            }
+            continue;
+        }
+
+        if let Some(defined) = get_definition(line, symbols, &mut aliases) {
+            if let Err(err) = defined {
+                errors.push(err);
+            }
+            continue;
+        }
+
+        let placed = get_direction(line, &aliases, options)
+            .and_then(|line| layout(&line, address, symbols).map(|place| (line, place)));
+        match placed {
+            Ok((mut line, (start, size))) => {
+                if start + size > MEMORY_END as u32 && !overflowed {
+                    errors.push(error(&line.name, format!("This goes past the end of memory at 0x{:X}.", MEMORY_END - 1)));
+                    overflowed = true;
                 }
-                "jp" => {
-                    let next = advance(&mut pos, &input);
-                    if !is_number(&next) && !is_label(&next) {panic!("Expected number or label after jmp.")}
-                    ins.push(vec!["jp".to_string(), next]);
-                },
-                "se" => {
-                    let vx = advance(&mut pos, &input);
-                    if !is_register(&vx) {panic!("Expected register after se.")}
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) && !is_number(&next) {panic!("Expected number or register on se.")}
-                    ins.push(vec!["se".to_string(), vx, next]);
-                },
-                "sne" => {
-                    let vx = advance(&mut pos, &input);
-                    if !is_register(&vx) {panic!("Expected register after sne.")}
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) && !is_number(&next) {panic!("Expected number or register on sne.")}
-                    ins.push(vec!["sne".to_string(), vx, next]);
-                },
-                "gt" => {
-                    let vx = advance(&mut pos, &input);
-                    let vy = advance(&mut pos, &input);
-                    if !is_register(&vx) || !is_register(&vy) {panic!("gt arguments are both registers.")}
-                    ins.push(vec!["gt".to_string(),vx, vy]);
-                },
-                "gte" => {
-                    let vx = advance(&mut pos, &input);
-                    let vy = advance(&mut pos, &input);
-                    if !is_register(&vx) || !is_register(&vy) {panic!("gte arguments are both registers.")}
-                    ins.push(vec!["gte".to_string(),vx, vy]);
-                },
-                "lt" => {
-                    let vx = advance(&mut pos, &input);
-                    let vy = advance(&mut pos, &input);
-                    if !is_register(&vx) || !is_register(&vy) {panic!("lt arguments are both registers.")}
-                    ins.push(vec!["lt".to_string(),vx, vy]);
-                },
-                "lte" => {
-                    let vx = advance(&mut pos, &input);
-                    let vy = advance(&mut pos, &input);
-                    if !is_register(&vx) || !is_register(&vy) {panic!("lte arguments are both registers.")}
-                    ins.push(vec!["lte".to_string(),vx, vy]);
-                },
-                "ld" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register after ld.")}
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) && !is_number(&next) { panic!("Expected number or register in ld"); }
-                    ins.push(vec!["ld".to_string(), reg, next]);
-                },
-                "ldi" => {
-                    let num = advance(&mut pos, &input);
-                    if !is_number(&num) {panic!("Expected number in ldi.")};
-                    ins.push(vec!["ldi".to_string(), num]);
-                },
-                "ldsprt" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in ldsprt.")};
-                    ins.push(vec!["ldsprt".to_string(), reg]);
-                },
-                "ldbcd" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in ldbcd.")};
-                    ins.push(vec!["ldbcd".to_string(), reg]);
-                },
-                "dumpreg" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in dumpreg.")};
-                    ins.push(vec!["dumpreg".to_string(), reg]);
-                },
-                "ldreg" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in ldreg.")};
-                    ins.push(vec!["ldreg".to_string(), reg]);
-                },
-                "getkey" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in getkey.")};
-                    ins.push(vec!["getkey".to_string(), reg]);
-                },
-                "add" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in add")};
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) && !is_number(&next) {panic!("Expected number or register in add.")}
-                    ins.push(vec!["add".to_string(), reg, next]);
+                line.address = start;
+                line.size = size;
+                address = start + size;
+                if line.name.text != "org" {
+                    ins.push(line);
                 }
-                "addi" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in addi.")};
-                    ins.push(vec!["addi".to_string(), reg]);
-                },
-                "sub" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in sub")};
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) {panic!("Expected register in sub.")}
-                    ins.push(vec!["sub".to_string(), reg, next]);
-                },
-                "subn" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in subn")};
-                    let next = advance(&mut pos, &input);
-                    if !is_register(&next) {panic!("Expected register in subn.")}
-                    ins.push(vec!["subn".to_string(), reg, next]);
-                },
-                "shr" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in shr.")};
-                    ins.push(vec!["shr".to_string(), reg]);
-                },
-                "shl" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in shl.")};
-                    ins.push(vec!["shl".to_string(), reg]);
-                },
-                "rnd" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in rnd")};
-                    let next = advance(&mut pos, &input);
-                    if !is_number(&next) {panic!("Expected number in rnd.")}
-                    ins.push(vec!["rnd".to_string(), reg, next]);
-                },
-                "drw" => {
-                    let vx = advance(&mut pos, &input);
-                    let vy = advance(&mut pos, &input);
-                    if !is_register(&vx) && !is_register(&vy) {panic!("Expected two register inputs to drw")};
-                    let num = advance(&mut pos, &input);
-                    if !is_number(&num) {panic!("Expected number in drw")}
-                    ins.push(vec!["drw".to_string(), vx, vy, num]);
-                },
-                "skp" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in skp.")};
-                    ins.push(vec!["skp".to_string(), reg]);
-                },
-                "sknp" => {
-                    let reg = advance(&mut pos, &input);
-                    if !is_register(&reg) {panic!("Expected register in sknp.")};
-                    ins.push(vec!["sknp".to_string(), reg]);
-                },
-                _ => panic!("Unknown instruction {}", input[pos]),
-            }
-            pos += 1;
-            instruction_num += 2;
+            },
+            Err(err) => {
+                // Count the broken instruction as two bytes so later labels
+                // stay close
+                errors.push(err);
+                address += 2;
+            },
         }
     }
 
@@ -429,70 +1170,321 @@ fn separate_into_directions(input: Vec<String>, label_table: &mut HashMap<String
     ins
 }
 
+// Where to point errors about something missing at the end of `line`
+fn end_of(line: &[Token]) -> Token {
+    let last = line.last().unwrap();
+    Token { text: String::new(), column: last.column + last.text.chars().count(), ..last.clone() }
+}
 
-fn get_label(input: &str, pos: &mut usize) -> String {
-    let mut word = String::new();
+// `define NAME value`, `NAME equ value` or `alias NAME vx`. None if `line`
+// isn't one of those.
+fn get_definition(line: &[Token], symbols: &mut Symbols, aliases: &mut HashMap<String, String>) -> Option<Result<(), Diagnostic>> {
+    let (keyword, name) = match line.get(1) {
+        Some(second) if second.text == "equ" => (second, Some(&line[0])),
+        _ if line[0].text == "define" || line[0].text == "alias" => (&line[0], line.get(1)),
+        _ => return None,
+    };
+    let value = &line[2.min(line.len())..];
 
-    word.push('.');
-    *pos += 1;
+    let name = match name {
+        Some(name) if is_name(&name.text) => name,
+        Some(name) => return Some(Err(error(name, format!("{} can't be used as a name.", name.text)))),
+        None => return Some(Err(error(&end_of(line), format!("Expected a name after {}.", keyword.text)))),
+    };
+    if value.is_empty() {
+        return Some(Err(error(&end_of(line), format!("Expected a value for {}.", name.text))));
+    }
+    if symbols.constants.contains_key(&name.text) || aliases.contains_key(&name.text) {
+        return Some(Err(error(name, format!("{} was already defined.", name.text))));
+    }
 
-    let mut ch = input.chars().nth(*pos).unwrap();
-    while *pos < input.len() && (char::is_alphanumeric(ch) || ch == '_') {
-        word.push(ch);
-        *pos += 1;
-        if *pos >= input.len() {break;}
-        ch = input.chars().nth(*pos).unwrap();
+    if keyword.text == "alias" {
+        let register = aliases.get(&value[0].text).unwrap_or(&value[0].text).clone();
+        if !is_register(&register) {
+            return Some(Err(error(&value[0], format!("Expected a register to alias, got {}.", value[0].text))));
+        }
+        if let Some(extra) = value.get(1) {
+            return Some(Err(error(extra, format!("Unexpected {} after the register.", extra.text))));
+        }
+        aliases.insert(name.text.clone(), register);
+    } else {
+        // Checked for syntax now, evaluated where it's used
+        if let Err(err) = evaluate(value, None, 0) {
+            return Some(Err(err));
+        }
+        symbols.constants.insert(name.text.clone(), value.to_vec());
     }
 
-    word
+    Some(Ok(()))
 }
 
-fn get_number(input: &str, pos: &mut usize) -> String {
-    let mut word = String::new();
+// Split the operands of the instruction or directive starting `line` and
+// check they're the right kinds
+fn get_direction(line: &[Token], aliases: &HashMap<String, String>, options: &Options) -> Result<Direction, Diagnostic> {
+    let mut name = line[0].clone();
+    let mut operands: Vec<Vec<Token>> = vec![];
 
-    while *pos < input.len() && char::is_numeric(input.chars().nth(*pos).unwrap()) {
-        word.push(input.chars().nth(*pos).unwrap());
-        *pos += 1;
+    let mut pos = 1;
+    // Tolerate a comma straight after the name, `ldi, 2000`
+    if line.get(pos).is_some_and(|token| token.text == ",") {
+        pos += 1;
     }
 
-    word
+    while pos < line.len() {
+        let token = &line[pos];
+        let len = if is_register(&token.text) || is_special(&token.text) || is_string(&token.text) || is_row(&token.text) || aliases.contains_key(&token.text) {
+            1
+        } else {
+            expression_length(&line[pos..])?
+        };
+
+        let mut operand = line[pos..pos + len].to_vec();
+        if let Some(register) = aliases.get(&operand[0].text) {
+            operand[0].text = register.clone();
+        }
+        operands.push(operand);
+        pos += len;
+
+        // Commas between operands are optional
+        if line.get(pos).is_some_and(|token| token.text == ",") {
+            pos += 1;
+            if pos == line.len() {
+                return Err(error(&end_of(line), format!("Missing operand for {}.", name.text)));
+            }
+        }
+    }
+
+    // Cowgod's spellings of instructions that have a mnemonic of their own
+    // here become that mnemonic, keeping the name they were written with for
+    // errors
+    let spelled = name.text.clone();
+    let special = |op: Option<&Vec<Token>>| -> String {
+        match op {
+            Some(op) if kind(op) == Kind::Special => op[0].text.clone(),
+            _ => String::new(),
+        }
+    };
+    let (first, second_special) = (special(operands.first()), special(operands.get(1)));
+    let short = match (name.text.as_str(), first.as_str(), second_special.as_str()) {
+        ("ld", "i", _) => Some(("ldi", 0)),
+        ("ld", "f", _) => Some(("ldsprt", 0)),
+        ("ld", "b", _) => Some(("ldbcd", 0)),
+        ("ld", "[i]", _) => Some(("dumpreg", 0)),
+        ("ld", _, "[i]") => Some(("ldreg", 1)),
+        ("ld", _, "k") => Some(("getkey", 1)),
+        ("add", "i", _) => Some(("addi", 0)),
+        _ => None,
+    };
+    if let Some((short, dropped)) = short {
+        if operands.len() == 2 {
+            name.text = short.to_string();
+            operands.remove(dropped);
+        }
+    }
+
+    let expect = |expected: &[Kind]| -> Result<(), Diagnostic> {
+        for (i, kind_wanted) in expected.iter().enumerate() {
+            let op = match operands.get(i) {
+                Some(op) => op,
+                None => return Err(error(&end_of(line), format!("Missing operand for {}.", spelled))),
+            };
+            let found = kind(op);
+            let ok = found == *kind_wanted || (*kind_wanted == Kind::Text && found == Kind::Value);
+            if !ok {
+                let what = match kind_wanted {
+                    Kind::Register => "a register",
+                    Kind::Special => "dt or st",
+                    Kind::Value => "a number or label",
+                    Kind::Text => "a number or string",
+                    Kind::Row => "a sprite row",
+                };
+                return Err(error(&op[0], format!("Expected {} in {}.", what, spelled)));
+            }
+        }
+        match operands.get(expected.len()) {
+            Some(extra) => Err(error(&extra[0], format!("Too many operands for {}.", spelled))),
+            None => Ok(()),
+        }
+    };
+
+    // Either a register or a value
+    let second = |operands: &[Vec<Token>]| -> Kind {
+        match operands.get(1) {
+            Some(op) if kind(op) == Kind::Register => Kind::Register,
+            _ => Kind::Value,
+        }
+    };
+
+    use Kind::*;
+    match name.text.as_str() {
+        "cls" | "ret" => expect(&[])?,
+        "call" | "ldi" | "sys" => expect(&[Value])?,
+        "org" | "align" | "reserve" | "ds" => expect(&[Value])?,
+        "jp" if operands.len() == 2 => {
+            // Bnnn, jumps to nnn + v0
+            expect(&[Register, Value])?;
+            if operands[0][0].text != "v0" {
+                return Err(error(&operands[0][0], "Only v0 can be added to a jump address.".to_string()));
+            }
+        },
+        "jp" => expect(&[Value])?,
+        "ld" if first == "dt" || first == "st" => expect(&[Special, Register])?,
+        "ld" if second_special == "dt" => expect(&[Register, Special])?,
+        "se" | "sne" | "add" | "ld" => expect(&[Register, second(&operands)])?,
+        "gt" | "gte" | "lt" | "lte" if !options.comparisons => {
+            return Err(error(&name, format!("{} is a non-standard instruction, enable the comparison extension to use it.", name.text)));
+        },
+        "gt" | "gte" | "lt" | "lte" | "sub" | "subn" | "or" | "and" | "xor" => expect(&[Register, Register])?,
+        // The second register only matters with the VIP shift quirk
+        "shr" | "shl" if operands.len() == 2 => expect(&[Register, Register])?,
+        "ldsprt" | "ldbcd" | "dumpreg" | "ldreg" | "getkey" | "addi" | "shr" | "shl" | "skp" | "sknp" => expect(&[Register])?,
+        "rnd" => expect(&[Register, Value])?,
+        "drw" => expect(&[Register, Register, Value])?,
+        // Data takes any number of operands
+        "db" | ".byte" | "dw" | ".word" | "sprite" => {
+            let each = match name.text.as_str() {
+                "db" | ".byte" => Text,
+                "dw" | ".word" => Value,
+                _ => Row,
+            };
+            if operands.is_empty() {
+                return Err(error(&name, format!("Expected at least one operand after {}.", name.text)));
+            }
+            expect(&vec![each; operands.len()])?;
+        },
+        _ => return Err(error(&name, format!("Unknown instruction {}", name.text))),
+    }
+
+    Ok(Direction { name, operands, address: 0, size: 0 })
 }
 
-fn get_iden(input: &str, pos: &mut usize) -> String {
+
+// Read characters from `chars[*pos]` while `accept` allows them, keeping
+// track of the column
+fn take_while(chars: &[char], pos: &mut usize, column: &mut usize, accept: impl Fn(char) -> bool) -> String {
     let mut word = String::new();
 
-    let mut ch = input.chars().nth(*pos).unwrap();
-    while *pos < input.len() && (char::is_alphanumeric(ch) || ch == '_') {
-        word.push(ch);
+    while *pos < chars.len() && accept(chars[*pos]) {
+        word.push(chars[*pos]);
         *pos += 1;
-        if *pos >= input.len() {break;}
-        ch = input.chars().nth(*pos).unwrap();
+        *column += 1;
     }
 
     word
 }
 
-fn get_instructions(input: &str) -> Vec<String> {
+fn get_instructions(input: &str, errors: &mut Vec<Diagnostic>) -> Vec<Token> {
+    let chars: Vec<char> = input.chars().collect();
     let mut pos = 0;
-    let mut ins: Vec<String> = vec![];
+    let mut line = 1;
+    let mut column = 1;
+    let mut ins: Vec<Token> = vec![];
 
+    let is_word = |c: char| char::is_alphanumeric(c) || c == '_';
 
-    while pos < input.len() {
-        let ch = input.chars().nth(pos).unwrap();
+    // Set by `sprite` until the end of its line, so # and . read as pixels
+    let mut bitmap = false;
+
+    while pos < chars.len() {
+        let ch = chars[pos];
+        let start = column;
 
         match ch {
-            '.' => ins.push(get_label(input, &mut pos)),
+            '\n' => {
+                pos += 1;
+                line += 1;
+                column = 1;
+                bitmap = false;
+            },
+            '#' | '.' if bitmap => {
+                let word = take_while(&chars, &mut pos, &mut column, |c| c == '#' || c == '.');
+                ins.push(Token::new(word, line, start));
+            },
+            '.' => {
+                pos += 1;
+                column += 1;
+                let word = take_while(&chars, &mut pos, &mut column, is_word);
+                ins.push(Token::new(format!(".{}", word), line, start));
+            },
             ';' => {
-                let mut curr_char = input.chars().nth(pos).unwrap();
-                while pos < input.len() && curr_char != '\n' {
+                take_while(&chars, &mut pos, &mut column, |c| c != '\n');
+            },
+            // Prefixes and digits in any base, checked when the value is used.
+            // % is only binary when a digit follows, otherwise it's modulo.
+            _ if char::is_numeric(ch) || ch == '$' || (ch == '%' && matches!(chars.get(pos + 1), Some('0') | Some('1'))) => {
+                pos += 1;
+                column += 1;
+                let word = take_while(&chars, &mut pos, &mut column, char::is_alphanumeric);
+                ins.push(Token::new(format!("{}{}", ch, word), line, start));
+            },
+            '\'' => {
+                let mut word = String::from("'");
+                let mut escaped = false;
+                pos += 1;
+                column += 1;
+                while pos < chars.len() && chars[pos] != '\n' {
+                    let c = chars[pos];
+                    word.push(c);
                     pos += 1;
-                    if pos >= input.len() {break;}
-                    curr_char = input.chars().nth(pos).unwrap();
+                    column += 1;
+                    if c == '\'' && !escaped {break;}
+                    escaped = c == '\\' && !escaped;
                 }
-            }
-            _ if char::is_numeric(ch) => ins.push(get_number(input, &mut pos)),
-            _ if char::is_alphabetic(ch) => ins.push(get_iden(input, &mut pos)),
-            _ => pos += 1,
+                ins.push(Token::new(word, line, start));
+            },
+            _ if char::is_alphabetic(ch) || ch == '_' => {
+                let word = take_while(&chars, &mut pos, &mut column, is_word);
+                bitmap = word == "sprite";
+                ins.push(Token::new(word, line, start));
+            },
+            '"' => {
+                let mut word = String::from("\"");
+                let mut escaped = false;
+                pos += 1;
+                column += 1;
+                while pos < chars.len() && chars[pos] != '\n' {
+                    let c = chars[pos];
+                    word.push(c);
+                    pos += 1;
+                    column += 1;
+                    if c == '"' && !escaped {break;}
+                    escaped = c == '\\' && !escaped;
+                }
+                ins.push(Token::new(word, line, start));
+            },
+            _ if char::is_whitespace(ch) => {
+                pos += 1;
+                column += 1;
+            },
+            // [i], with any spacing inside the brackets
+            '[' => {
+                pos += 1;
+                column += 1;
+                let inside = take_while(&chars, &mut pos, &mut column, |c| c != ']' && c != '\n');
+                if pos < chars.len() && chars[pos] == ']' {
+                    pos += 1;
+                    column += 1;
+                }
+                let inside: String = inside.chars().filter(|c| !c.is_whitespace()).collect();
+                ins.push(Token::new(format!("[{}]", inside), line, start));
+            },
+            '<' | '>' if chars.get(pos + 1) == Some(&ch) => {
+                pos += 2;
+                column += 2;
+                ins.push(Token::new(format!("{}{}", ch, ch), line, start));
+            },
+            // Operators, and commas which separate operands
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '(' | ')' | ',' => {
+                pos += 1;
+                column += 1;
+                ins.push(Token::new(ch.to_string(), line, start));
+            },
+            _ => {
+                let token = Token::new(ch.to_string(), line, start);
+                errors.push(error(&token, format!("Unexpected character {}", ch)));
+                pos += 1;
+                column += 1;
+            },
         }
     }
 
@@ -501,3 +1493,371 @@ fn get_instructions(input: &str) -> Vec<String> {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_ok(source: &str) -> Vec<u8> {
+        assemble(source, "test.asm", &Options::default()).unwrap_or_else(|errors| panic!("{}", errors[0]))
+    }
+
+    fn errors(source: &str) -> Vec<Diagnostic> {
+        match assemble(source, "test.asm", &Options::default()) {
+            Ok(rom) => panic!("expected errors, assembled {:02x?}", rom),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn clean_source_has_no_diagnostics() {
+        let rom = assemble_ok("\
+.start
+    cls
+    ld v0, 5
+    jp .start
+");
+        assert_eq!(rom, vec![0x00, 0xE0, 0x60, 5, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn diagnostics_point_at_every_error() {
+        let errors = errors("cls\n  ld v0, 300\n  jp\nfoo v1\n");
+
+        let found: Vec<(usize, usize)> = errors.iter().map(|err| (err.line, err.column)).collect();
+        assert_eq!(found, vec![(2, 10), (3, 5), (4, 1)]);
+        assert_eq!(errors[0].message, "Expected a number between -128 and 255, got 300.");
+        assert_eq!(errors[0].file, "test.asm");
+        assert_eq!(errors[0].snippet, "  ld v0, 300");
+        assert_eq!(errors[1].message, "Missing operand for jp.");
+        assert_eq!(errors[2].message, "Unknown instruction foo");
+    }
+
+    #[test]
+    fn number_literals() {
+        let rom = assemble_ok("\
+    ld v0, 42
+    ld v1, 0x2A
+    ld v2, $2a
+    ld v3, 0b101010
+    ld v4, %101010
+    ld v5, 'A'
+    ld v6, '\\''
+    ld v7, '\\\\'
+    ldi 0X2A0
+");
+        assert_eq!(rom, vec![
+            0x60, 42, 0x61, 42, 0x62, 42, 0x63, 42, 0x64, 42,
+            0x65, b'A', 0x66, b'\'', 0x67, b'\\', 0xA2, 0xA0,
+        ]);
+    }
+
+    #[test]
+    fn malformed_literals() {
+        let errors = errors("\
+    ld v0, 0x2G
+    ld v0, $
+    ld v0, 0b102
+    ld v0, %2
+    ld v0, 'AB'
+    ld v0, ''
+    ld v0, 'A
+");
+        let messages: Vec<&str> = errors.iter().map(|err| err.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Invalid number 0x2G.",
+            "Invalid number $.",
+            "Invalid number 0b102.",
+            // % followed by anything but a binary digit is modulo
+            "Invalid number %.",
+            "Invalid number 'AB'.",
+            "Invalid number ''.",
+            "Invalid number 'A.",
+        ]);
+        assert_eq!((errors[1].line, errors[1].column), (2, 12));
+    }
+
+    #[test]
+    fn data_directives() {
+        let rom = assemble_ok(r#"
+    ldi .ball
+.text
+    db "Hi\n\t\0\\\"", 1, -1
+    .byte 'x'
+.words
+    dw 0x1234, .text, -2
+    .word .words
+.ball
+    sprite #.#..... ########
+    sprite ##.. ########.......#
+"#);
+        assert_eq!(rom, vec![
+            0xA2, 0x14,
+            b'H', b'i', b'\n', b'\t', 0, b'\\', b'"', 1, 0xFF,
+            b'x',
+            0x12, 0x34, 0x02, 0x02, 0xFF, 0xFE,
+            0x02, 0x0C,
+            0xA0, 0xFF,
+            0xC0, 0xFF, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn bad_data() {
+        let errors = errors(r#"
+    db "\q"
+    db "open
+    sprite #################
+"#);
+        let messages: Vec<&str> = errors.iter().map(|err| err.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Invalid string \"\\q\".",
+            "Unterminated string.",
+            "Sprite rows can be at most 16 pixels wide, this one is 17.",
+        ]);
+    }
+
+    #[test]
+    fn labels_as_addresses() {
+        let rom = assemble_ok("\
+    jp v0, .table
+    call .table + 2
+    ldi .table
+.table
+    jp .table
+");
+        assert_eq!(rom, vec![0xB2, 0x06, 0x22, 0x08, 0xA2, 0x06, 0x12, 0x06]);
+    }
+
+    #[test]
+    fn labels_past_0xfff_are_errors() {
+        let errors = errors("\
+    jp v0, .high
+    call .high
+    ldi .high
+    jp .high
+    org 0xFFF
+    db 1
+.high
+");
+        assert_eq!(errors.len(), 4);
+        for (line, err) in errors.iter().enumerate() {
+            assert_eq!(err.line, line + 1);
+            assert_eq!(err.message, "Address must be between 0 and 0xFFF, got .high (0x1000).");
+        }
+    }
+
+    #[test]
+    fn constants_and_aliases() {
+        let rom = assemble_ok("\
+SPEED equ 3
+define START 0x300
+alias ball_x v2
+alias x ball_x
+    ld ball_x, SPEED
+    add x, ball_x
+    ldi START + SPEED
+");
+        assert_eq!(rom, vec![0x62, 3, 0x82, 0x24, 0xA3, 0x03]);
+    }
+
+    #[test]
+    fn redefinitions_are_errors() {
+        let errors = errors("\
+SPEED equ 3
+SPEED equ 4
+define SPEED 5
+alias SPEED v1
+alias ball v2
+ball equ 1
+alias paddle i
+.start
+.start
+");
+        let found: Vec<(usize, &str)> = errors.iter().map(|err| (err.line, err.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (2, "SPEED was already defined."),
+            (3, "SPEED was already defined."),
+            (4, "SPEED was already defined."),
+            (6, "ball was already defined."),
+            (7, "Expected a register to alias, got i."),
+            (9, "Label .start was already defined."),
+        ]);
+    }
+
+    #[test]
+    fn expressions() {
+        let rom = assemble_ok("\
+WIDTH equ 64
+HALF equ WIDTH / 2
+    ld v0, HALF - 4
+    ld v1, (1 + 2) * 3
+    ld v2, -1
+    ld v3, 10 % 3
+    ld v4, 1 << 4 | ~$FE & 3
+    ldi .data + 1
+.data
+    db low(.data), high(.data)
+");
+        assert_eq!(rom, vec![0x60, 28, 0x61, 9, 0x62, 0xFF, 0x63, 1, 0x64, 0x11, 0xA2, 0x0D, 0x0C, 0x02]);
+    }
+
+    #[test]
+    fn values_must_fit_their_field() {
+        let errors = errors("\
+    ld v0, 256
+    ld v0, 100 + 200
+    ldi 0x1000
+    ld v0, 1 / 0
+    dw $FFFFFFFF * $FFFFFFFF * $FFFF
+    ld v0, (1 + 2
+");
+        let messages: Vec<&str> = errors.iter().map(|err| err.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Expected a number between -128 and 255, got 256.",
+            "Expected a number between -128 and 255, got 100 + 200 (0x12C).",
+            "Address must be between 0 and 0xFFF, got 0x1000.",
+            "Division by zero.",
+            "Expression overflows.",
+            "This ( is never closed.",
+        ]);
+    }
+
+    #[test]
+    fn cowgod_spellings() {
+        let rom = assemble_ok("\
+    sys 0x123
+    jp v0, 0x300
+    shr v1, v2
+    shl v1, v2
+    ld [i], v5
+    ld v5, [i]
+    ld f, v3
+    ld b, v3
+    ld i, 0x2A0
+    add i, v4
+    ld dt, v6
+    ld st, v6
+    ld v7, dt
+    ld v7, k
+");
+        assert_eq!(rom, vec![
+            0x01, 0x23, 0xB3, 0x00, 0x81, 0x26, 0x81, 0x2E,
+            0xF5, 0x55, 0xF5, 0x65, 0xF3, 0x29, 0xF3, 0x33,
+            0xA2, 0xA0, 0xF4, 0x1E, 0xF6, 0x15, 0xF6, 0x18,
+            0xF7, 0x07, 0xF7, 0x0A,
+        ]);
+
+        // The older mnemonics still mean the same thing
+        let native = assemble_ok("\
+    sys 0x123
+    jp v0, 0x300
+    shr v1, v2
+    shl v1, v2
+    dumpreg v5
+    ldreg v5
+    ldsprt v3
+    ldbcd v3
+    ldi 0x2A0
+    addi v4
+    ld dt, v6
+    ld st, v6
+    ld v7, dt
+    getkey v7
+");
+        assert_eq!(rom, native);
+    }
+
+    #[test]
+    fn org_align_and_reserve() {
+        let (rom, map) = assemble_with_map("\
+    jp .main
+    align 8
+.buffer
+    reserve 4
+.main
+    ldi .buffer
+    org 0x210
+    db 1
+", "test.asm", &Options::default()).unwrap_or_else(|errors| panic!("{}", errors[0]));
+
+        let mut expected = vec![0; 0x11];
+        expected[0..2].copy_from_slice(&[0x12, 0x0C]);
+        expected[0xC..0xE].copy_from_slice(&[0xA2, 0x08]);
+        expected[0x10] = 1;
+        assert_eq!(rom, expected);
+
+        let regions: Vec<(u16, u16, RegionKind)> = map.regions.iter().map(|region| (region.start, region.end, region.kind)).collect();
+        assert_eq!(regions, vec![
+            (0x200, 0x202, RegionKind::Code),
+            (0x202, 0x20C, RegionKind::Reserved),
+            (0x20C, 0x20E, RegionKind::Code),
+            (0x210, 0x211, RegionKind::Data),
+        ]);
+        assert_eq!(map.regions[1].labels, vec![".buffer"]);
+    }
+
+    #[test]
+    fn overlapping_org_is_an_error() {
+        let errors = errors("\
+    org 0x300
+    db 1, 2, 3
+    org 0x301
+    db 4
+");
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].line, errors[0].column), (4, 5));
+        assert_eq!(errors[0].message, "Bytes 0x301 - 0x301 are already used by line 2.");
+    }
+
+    #[test]
+    fn macro_labels_are_local_to_each_expansion() {
+        let rom = assemble_ok("\
+macro countdown reg, n
+    ld reg, n
+.loop
+    add reg, -1
+    se reg, 0
+    jp .loop
+endm
+
+    countdown v0, 3
+    countdown v1, 4 + 1
+");
+        assert_eq!(rom, vec![
+            0x60, 3, 0x70, 0xFF, 0x30, 0, 0x12, 0x02,
+            0x61, 5, 0x71, 0xFF, 0x31, 0, 0x12, 0x0A,
+        ]);
+    }
+
+    #[test]
+    fn macro_errors_point_at_call_and_definition() {
+        let errors = errors("\
+macro set reg
+    ld reg, 300
+endm
+
+macro forever
+    forever
+endm
+
+    set v0
+    set
+    forever
+");
+        assert_eq!(errors.len(), 3);
+
+        assert_eq!((errors[0].line, errors[0].column), (9, 5));
+        assert_eq!(errors[0].notes.len(), 1);
+        assert_eq!((errors[0].notes[0].line, errors[0].notes[0].column), (2, 13));
+        assert_eq!(errors[0].notes[0].message, "in macro set");
+
+        assert_eq!((errors[1].line, errors[1].column), (10, 5));
+        assert_eq!(errors[1].message, "set takes 1 argument(s), got 0.");
+        assert_eq!((errors[1].notes[0].line, errors[1].notes[0].column), (1, 7));
+
+        assert_eq!((errors[2].line, errors[2].column), (11, 5));
+        assert!(errors[2].message.starts_with("Macros nest more than 16 deep"));
+        assert_eq!(errors[2].notes.len(), 1);
+        assert_eq!(errors[2].notes[0].line, 6);
+    }
+}
@@ -0,0 +1,223 @@
+use std::{rc::Rc, cell::RefCell, fs::File, io::{self, Write}};
+
+use crate::backend::Beeper;
+use crate::emulator::TIMER_HZ;
+
+#[derive(Clone, Copy)]
+pub struct AudioSettings {
+    // Frequency of the beep in Hz
+    pub pitch: f32,
+    // 0.0 is silent, 1.0 is full scale
+    pub volume: f32,
+    pub sample_rate: u32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings { pitch: 440.0, volume: 0.25, sample_rate: 44100 }
+    }
+}
+
+// Square wave at the configured pitch, or an XO-CHIP 1-bit pattern once one
+// has been set.
+pub struct SquareWave {
+    settings: AudioSettings,
+    // Position within the current period, 0.0 to 1.0
+    phase: f32,
+    // 128 bit pattern and how many of its bits play per second
+    pattern: Option<([u8; 16], f32)>,
+}
+
+impl SquareWave {
+    pub fn new(settings: AudioSettings) -> SquareWave {
+        SquareWave { settings, phase: 0.0, pattern: None }
+    }
+
+    // None goes back to the plain tone
+    pub fn set_pattern(&mut self, pattern: Option<[u8; 16]>, rate: f32) {
+        self.pattern = pattern.map(|pattern| (pattern, rate));
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let (high, frequency) = match &self.pattern {
+            Some((pattern, rate)) => {
+                let bit = (self.phase * 128.0) as usize % 128;
+                ((pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1, rate / 128.0)
+            },
+            None => (self.phase < 0.5, self.settings.pitch),
+        };
+
+        self.phase = (self.phase + frequency / self.settings.sample_rate as f32).fract();
+        if high {self.settings.volume} else {-self.settings.volume}
+    }
+}
+
+
+struct Recording {
+    wave: SquareWave,
+    beeping: bool,
+    samples: Vec<i16>,
+    ticks: Vec<bool>,
+}
+
+// Beeper that renders the tone to memory instead of a device, for headless
+// runs. Clones share the same recording, so keep one and give the other
+// to the emulator.
+#[derive(Clone)]
+pub struct WavRecorder {
+    recording: Rc<RefCell<Recording>>,
+    settings: AudioSettings,
+}
+
+impl WavRecorder {
+    pub fn new(settings: AudioSettings) -> WavRecorder {
+        WavRecorder {
+            recording: Rc::new(RefCell::new(Recording {
+                wave: SquareWave::new(settings),
+                beeping: false,
+                samples: vec![],
+                ticks: vec![],
+            })),
+            settings,
+        }
+    }
+
+    // Whether the beeper was on for each timer tick so far
+    pub fn ticks(&self) -> Vec<bool> {
+        self.recording.borrow().ticks.clone()
+    }
+
+    pub fn samples(&self) -> Vec<i16> {
+        self.recording.borrow().samples.clone()
+    }
+
+    // Write the recording as 16-bit mono PCM
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let recording = self.recording.borrow();
+        let data_len = recording.samples.len() as u32 * 2;
+        let rate = self.settings.sample_rate;
+
+        let mut out: Vec<u8> = vec![];
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(36 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());       // PCM
+        out.extend_from_slice(&1u16.to_le_bytes());       // Mono
+        out.extend_from_slice(&rate.to_le_bytes());
+        out.extend_from_slice(&(rate * 2).to_le_bytes()); // Byte rate
+        out.extend_from_slice(&2u16.to_le_bytes());       // Block align
+        out.extend_from_slice(&16u16.to_le_bytes());      // Bits per sample
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        for sample in &recording.samples {
+            out.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        File::create(path)?.write_all(&out)
+    }
+}
+
+impl Beeper for WavRecorder {
+    fn set_beeping(&mut self, beeping: bool) {
+        self.recording.borrow_mut().beeping = beeping;
+    }
+
+    fn set_pattern(&mut self, pattern: Option<[u8; 16]>, rate: f32) {
+        self.recording.borrow_mut().wave.set_pattern(pattern, rate);
+    }
+
+    fn tick(&mut self) {
+        let mut recording = self.recording.borrow_mut();
+        let beeping = recording.beeping;
+        recording.ticks.push(beeping);
+
+        for _ in 0..self.settings.sample_rate / TIMER_HZ {
+            let sample = recording.wave.next_sample();
+            let sample = if beeping {(sample * i16::MAX as f32) as i16} else {0};
+            recording.samples.push(sample);
+        }
+    }
+}
+
+
+// Beeper that plays through the default output device.
+#[cfg(feature = "audio")]
+pub struct DeviceBeeper {
+    beeping: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    wave: std::sync::Arc<std::sync::Mutex<SquareWave>>,
+    _stream: cpal::Stream,
+}
+
+#[cfg(feature = "audio")]
+impl DeviceBeeper {
+    pub fn new(settings: AudioSettings) -> Option<DeviceBeeper> {
+        use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+        use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
+
+        let device = cpal::default_host().default_output_device()?;
+        let mut config = device.default_output_config().ok()?.config();
+        config.channels = 1;
+
+        let wave = Arc::new(Mutex::new(SquareWave::new(AudioSettings { sample_rate: config.sample_rate.0, ..settings })));
+        let beeping = Arc::new(AtomicBool::new(false));
+        let playing = beeping.clone();
+        let stream_wave = wave.clone();
+
+        let stream = device.build_output_stream(
+            &config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let on = playing.load(Ordering::Relaxed);
+                let mut wave = stream_wave.lock().unwrap();
+                for sample in data.iter_mut() {
+                    let next = wave.next_sample();
+                    *sample = if on {next} else {0.0};
+                }
+            },
+            |err| eprintln!("Audio stream error: {}", err),
+        ).ok()?;
+        stream.play().ok()?;
+
+        Some(DeviceBeeper { beeping, wave, _stream: stream })
+    }
+}
+
+#[cfg(feature = "audio")]
+impl Beeper for DeviceBeeper {
+    fn set_beeping(&mut self, beeping: bool) {
+        self.beeping.store(beeping, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn set_pattern(&mut self, pattern: Option<[u8; 16]>, rate: f32) {
+        self.wave.lock().unwrap().set_pattern(pattern, rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Chip8;
+
+    #[test]
+    fn sound_timer_beeps_for_st_ticks() {
+        let settings = AudioSettings::default();
+        let recorder = WavRecorder::new(settings);
+        let mut chip8 = Chip8::new();
+        chip8.set_beeper(Box::new(recorder.clone()));
+        // ld v0, 3; ld st, v0; loop forever
+        chip8.load_rom_data(vec![0x60, 0x03, 0xF0, 0x18, 0x12, 0x04]).unwrap();
+
+        for _ in 0..6 {
+            chip8.run_frame().unwrap();
+        }
+
+        assert_eq!(recorder.ticks(), vec![true, true, true, false, false, false]);
+
+        let per_tick = (settings.sample_rate / TIMER_HZ) as usize;
+        let samples = recorder.samples();
+        assert_eq!(samples.len(), 6 * per_tick);
+        assert!(samples[..3 * per_tick].iter().any(|sample| *sample != 0));
+        assert!(samples[3 * per_tick..].iter().all(|sample| *sample == 0));
+    }
+}
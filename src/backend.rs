@@ -0,0 +1,63 @@
+use std::{rc::Rc, cell::Cell};
+
+// Receives the framebuffer whenever the emulator changes it. Pixels are one
+// byte each, row major, width * height long. Bit 0 of a pixel is the first
+// plane and bit 1 the second, so XO-CHIP frames have values 0 to 3.
+pub trait Display {
+    fn draw(&mut self, frame: &[u8], width: usize, height: usize);
+}
+
+// Source of the 16 key hex keypad.
+pub trait Keypad {
+    fn is_key_down(&self, key: u8) -> bool;
+}
+
+// Told when the sound timer starts and stops the tone. tick is called once
+// per 60 Hz timer tick, for beepers that need to keep time themselves.
+pub trait Beeper {
+    fn set_beeping(&mut self, beeping: bool);
+    fn tick(&mut self) {}
+
+    // XO-CHIP audio: play this 128 bit pattern, one bit per sample at
+    // `rate` samples per second, instead of the plain tone. None goes back
+    // to the plain tone.
+    fn set_pattern(&mut self, _pattern: Option<[u8; 16]>, _rate: f32) {}
+}
+
+
+pub struct NullDisplay;
+
+impl Display for NullDisplay {
+    fn draw(&mut self, _frame: &[u8], _width: usize, _height: usize) {}
+}
+
+pub struct NullBeeper;
+
+impl Beeper for NullBeeper {
+    fn set_beeping(&mut self, _beeping: bool) {}
+}
+
+// Plain key state that can be shared between the emulator and whatever is
+// producing key presses. Clones refer to the same keys.
+#[derive(Clone, Default)]
+pub struct SharedKeypad {
+    keys: Rc<Cell<[bool; 16]>>,
+}
+
+impl SharedKeypad {
+    pub fn new() -> SharedKeypad {
+        SharedKeypad::default()
+    }
+
+    pub fn set_key(&self, key: u8, down: bool) {
+        let mut keys = self.keys.get();
+        keys[(key & 0xF) as usize] = down;
+        self.keys.set(keys);
+    }
+}
+
+impl Keypad for SharedKeypad {
+    fn is_key_down(&self, key: u8) -> bool {
+        self.keys.get()[(key & 0xF) as usize]
+    }
+}
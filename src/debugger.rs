@@ -0,0 +1,603 @@
+use std::io::{self, Write, BufRead};
+
+use crate::backend::SharedKeypad;
+use crate::emulator::Chip8;
+use crate::error::Chip8Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub kind: WatchKind,
+}
+
+// What a condition breakpoint looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    V(usize),
+    I,
+    Sp,
+    Dt,
+    St,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// Break when e.g. V6 == 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    fn holds(&self, chip8: &Chip8) -> bool {
+        let current = match self.operand {
+            Operand::V(reg) => chip8.registers()[reg] as u16,
+            Operand::I => chip8.i(),
+            Operand::Sp => chip8.sp() as u16,
+            Operand::Dt => chip8.delay_timer() as u16,
+            Operand::St => chip8.sound_timer() as u16,
+        };
+
+        match self.comparison {
+            Comparison::Eq => current == self.value,
+            Comparison::Ne => current != self.value,
+            Comparison::Lt => current < self.value,
+            Comparison::Le => current <= self.value,
+            Comparison::Gt => current > self.value,
+            Comparison::Ge => current >= self.value,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint { addr: usize, write: bool },
+    // Index into the conditions that became true
+    Condition(usize),
+    // Finished a step, step over or step out
+    Stepped,
+    // Ran out of frames before anything else stopped it
+    Paused,
+    // 00FD
+    Halted,
+    Error(Chip8Error),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+    conditions: Vec<Condition>,
+    paused: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: vec![],
+            watchpoints: vec![],
+            conditions: vec![],
+            paused: true,
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|bp| *bp != addr);
+        len != self.breakpoints.len()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|wp| wp.addr != addr);
+        len != self.watchpoints.len()
+    }
+
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.conditions.push(condition);
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> bool {
+        if index >= self.conditions.len() {return false;}
+        self.conditions.remove(index);
+        true
+    }
+
+    // Run one instruction with Chip8::step, so timers keep their 60 Hz pace
+    // relative to the cpu. Ok(true) when it ended a frame. Stops on
+    // watchpoints and conditions, but not breakpoints, so stepping off a
+    // breakpoint works.
+    fn execute(&mut self, chip8: &mut Chip8) -> Result<bool, StopReason> {
+        if chip8.is_halted() {
+            return Err(StopReason::Halted);
+        }
+
+        chip8.set_access_tracking(!self.watchpoints.is_empty());
+        let conditions_before: Vec<bool> = self.conditions.iter().map(|c| c.holds(chip8)).collect();
+
+        let ticked = chip8.step().map_err(StopReason::Error)?;
+
+        for access in chip8.accesses() {
+            for wp in &self.watchpoints {
+                let hit = match wp.kind {
+                    WatchKind::Read => !access.write,
+                    WatchKind::Write => access.write,
+                    WatchKind::Access => true,
+                };
+                if hit && wp.addr == access.addr {
+                    return Err(StopReason::Watchpoint { addr: access.addr, write: access.write });
+                }
+            }
+        }
+
+        // Only break when a condition becomes true, not on every instruction
+        // while it stays true
+        for (index, condition) in self.conditions.iter().enumerate() {
+            if !conditions_before[index] && condition.holds(chip8) {
+                return Err(StopReason::Condition(index));
+            }
+        }
+
+        Ok(ticked)
+    }
+
+    fn at_breakpoint(&self, chip8: &Chip8) -> Option<StopReason> {
+        if self.breakpoints.contains(&chip8.pc()) {
+            Some(StopReason::Breakpoint(chip8.pc()))
+        } else {None}
+    }
+
+    pub fn step(&mut self, chip8: &mut Chip8) -> StopReason {
+        self.execute(chip8).err().unwrap_or(StopReason::Stepped)
+    }
+
+    // Like step, but runs a whole 2nnn call until it returns, giving up
+    // after `frames` frames
+    pub fn step_over(&mut self, chip8: &mut Chip8, frames: u32) -> StopReason {
+        let is_call = matches!(chip8.next_opcode(), Some(op) if op & 0xF000 == 0x2000);
+        if !is_call {
+            return self.step(chip8);
+        }
+
+        let (return_to, depth) = (chip8.pc().wrapping_add(2), chip8.sp());
+        self.run_until(chip8, frames, |chip8| chip8.pc() == return_to && chip8.sp() == depth)
+    }
+
+    // Run until the current subroutine returns to its caller, or `frames`
+    // frames go by
+    pub fn step_out(&mut self, chip8: &mut Chip8, frames: u32) -> StopReason {
+        let depth = chip8.sp();
+        if depth == 0 {
+            return self.step(chip8);
+        }
+        self.run_until(chip8, frames, |chip8| chip8.sp() < depth)
+    }
+
+    // Run up to `frames` frames until something stops us, pausing again
+    // after. The first instruction runs even on a breakpoint so continuing
+    // from one works.
+    pub fn resume_for(&mut self, chip8: &mut Chip8, frames: u32) -> StopReason {
+        let reason = self.run_until(chip8, frames, |_| false);
+        self.pause();
+        reason
+    }
+
+    fn run_until(&mut self, chip8: &mut Chip8, frames: u32, done: impl Fn(&Chip8) -> bool) -> StopReason {
+        let mut frames_left = frames;
+        loop {
+            match self.execute(chip8) {
+                Err(reason) => return reason,
+                Ok(true) => frames_left = frames_left.saturating_sub(1),
+                Ok(false) => {},
+            }
+
+            if done(chip8) {
+                return StopReason::Stepped;
+            }
+            if let Some(reason) = self.at_breakpoint(chip8) {
+                return reason;
+            }
+            if frames_left == 0 {
+                return StopReason::Paused;
+            }
+        }
+    }
+
+    // For frontends that drive emulation a frame at a time. Runs until the
+    // end of the current frame unless paused, pausing if anything stops it.
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Option<StopReason> {
+        if self.paused {
+            return None;
+        }
+
+        loop {
+            if let Some(reason) = self.at_breakpoint(chip8) {
+                self.paused = true;
+                return Some(reason);
+            }
+            match self.execute(chip8) {
+                Err(reason) => {
+                    self.paused = true;
+                    return Some(reason);
+                },
+                Ok(true) => return None,
+                Ok(false) => {},
+            }
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+
+fn parse_number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_operand(s: &str) -> Option<Operand> {
+    match s {
+        "i" => Some(Operand::I),
+        "sp" => Some(Operand::Sp),
+        "dt" => Some(Operand::Dt),
+        "st" => Some(Operand::St),
+        _ => {
+            let reg = s.strip_prefix('v')?;
+            let num = u8::from_str_radix(reg, 16).ok().filter(|_| reg.len() == 1)
+                .or_else(|| reg.parse().ok().filter(|num| *num < 16))?;
+            Some(Operand::V(num as usize))
+        }
+    }
+}
+
+fn parse_comparison(s: &str) -> Option<Comparison> {
+    match s {
+        "==" => Some(Comparison::Eq),
+        "!=" => Some(Comparison::Ne),
+        "<" => Some(Comparison::Lt),
+        "<=" => Some(Comparison::Le),
+        ">" => Some(Comparison::Gt),
+        ">=" => Some(Comparison::Ge),
+        _ => None,
+    }
+}
+
+fn print_state(chip8: &Chip8) {
+    let regs = chip8.registers();
+    for row in 0..2 {
+        let line: Vec<String> = (row * 8..row * 8 + 8).map(|r| format!("V{:X}={:02x}", r, regs[r])).collect();
+        println!("  {}", line.join(" "));
+    }
+    println!("  PC={:04x} I={:04x} SP={} DT={} ST={}", chip8.pc(), chip8.i(), chip8.sp(), chip8.delay_timer(), chip8.sound_timer());
+    let stack: Vec<String> = chip8.stack().iter().map(|addr| format!("{:04x}", addr)).collect();
+    println!("  stack [{}]", stack.join(" "));
+}
+
+fn print_location(chip8: &Chip8) {
+    match chip8.next_opcode() {
+        Some(op) => println!("{:04x}: {:04x}", chip8.pc(), op),
+        None => println!("{:04x}: <out of memory>", chip8.pc()),
+    }
+}
+
+fn print_stop(reason: &StopReason, debugger: &Debugger) {
+    match reason {
+        StopReason::Breakpoint(addr) => println!("Breakpoint at {:04x}", addr),
+        StopReason::Watchpoint { addr, write } => println!("Watchpoint: {} {:04x}", if *write {"write to"} else {"read from"}, addr),
+        StopReason::Condition(index) => println!("Condition {} hit: {:?}", index, debugger.conditions[*index]),
+        StopReason::Stepped => (),
+        StopReason::Paused => println!("Paused, nothing stopped it. Use `key` if it's waiting for input."),
+        StopReason::Halted => println!("Program exited (00FD)"),
+        StopReason::Error(err) => println!("Error: {}", err),
+    }
+}
+
+// How long next, out and continue run before pausing, so a ROM waiting for
+// a key or looping forever doesn't hang the prompt
+const RUN_FRAMES: u32 = 600;
+
+const HELP: &str = "\
+commands:
+  s, step [n]             run n instructions (default 1)
+  n, next                 step over a call
+  o, out                  run until the current subroutine returns
+  c, continue [frames]    run until a breakpoint, watchpoint or condition.
+                          next, out and continue pause after 600 frames
+                          (10 seconds) by default
+  k, key <n> [up|down]    press or release hex key n
+  b, break <addr>         break when pc reaches addr
+  d, delete <addr>        remove the breakpoint or watchpoint at addr
+  w, watch <addr> [r|w]   break on reads (r), writes (w) or both of addr
+  when <reg> <op> <n>     break when a register comparison becomes true,
+                          e.g. `when v6 == 1`. reg is v0-vf, i, sp, dt or st
+  unwhen <index>          remove a condition
+  l, list                 show breakpoints, watchpoints and conditions
+  r, regs                 show registers
+  m, mem <addr> [len]     dump memory
+  screen                  print the framebuffer
+  q, quit                 exit";
+
+// Terminal front end for the debugger. Reads commands from stdin until quit
+// or end of input.
+pub fn repl(chip8: &mut Chip8) {
+    let mut debugger = Debugger::new();
+    let stdin = io::stdin();
+
+    let keypad = SharedKeypad::new();
+    chip8.set_keypad(Box::new(keypad.clone()));
+
+    println!("Chip8 debugger, `help` for commands.");
+    print_location(chip8);
+
+    loop {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let args: Vec<String> = line.split_whitespace().map(|arg| arg.to_lowercase()).collect();
+        if args.is_empty() {
+            continue;
+        }
+
+        let stop = match args[0].as_str() {
+            "s" | "step" => {
+                let count = args.get(1).and_then(|n| parse_number(n)).unwrap_or(1);
+                let mut reason = StopReason::Stepped;
+                for _ in 0..count {
+                    reason = debugger.step(chip8);
+                    if !matches!(reason, StopReason::Stepped) {break;}
+                }
+                Some(reason)
+            },
+            "n" | "next" => Some(debugger.step_over(chip8, RUN_FRAMES)),
+            "o" | "out" => Some(debugger.step_out(chip8, RUN_FRAMES)),
+            "c" | "continue" => {
+                let frames = args.get(1).and_then(|n| parse_number(n)).map_or(RUN_FRAMES, |n| n as u32);
+                Some(debugger.resume_for(chip8, frames))
+            },
+            "k" | "key" => {
+                let down = match args.get(2).map(|state| state.as_str()) {
+                    None | Some("down") => Some(true),
+                    Some("up") => Some(false),
+                    _ => None,
+                };
+                match (args.get(1).and_then(|key| u8::from_str_radix(key, 16).ok()).filter(|key| *key < 16), down) {
+                    (Some(key), Some(down)) => keypad.set_key(key, down),
+                    _ => println!("Usage: key <0-f> [up|down]"),
+                }
+                None
+            },
+            "b" | "break" => {
+                match args.get(1).and_then(|addr| parse_number(addr)) {
+                    Some(addr) => debugger.add_breakpoint(addr),
+                    None => println!("Usage: break <addr>"),
+                }
+                None
+            },
+            "d" | "delete" => {
+                match args.get(1).and_then(|addr| parse_number(addr)) {
+                    Some(addr) => {
+                        let removed = debugger.remove_breakpoint(addr) | debugger.remove_watchpoint(addr as usize);
+                        if !removed {println!("Nothing set at {:04x}", addr)}
+                    },
+                    None => println!("Usage: delete <addr>"),
+                }
+                None
+            },
+            "w" | "watch" => {
+                let kind = match args.get(2).map(|kind| kind.as_str()) {
+                    Some("r") => Some(WatchKind::Read),
+                    Some("w") => Some(WatchKind::Write),
+                    None | Some("rw") => Some(WatchKind::Access),
+                    _ => None,
+                };
+                match (args.get(1).and_then(|addr| parse_number(addr)), kind) {
+                    (Some(addr), Some(kind)) => debugger.add_watchpoint(Watchpoint { addr: addr as usize, kind }),
+                    _ => println!("Usage: watch <addr> [r|w]"),
+                }
+                None
+            },
+            "when" => {
+                let condition = match args.as_slice() {
+                    [_, operand, comparison, value] => parse_operand(operand).and_then(|operand| {
+                        Some(Condition { operand, comparison: parse_comparison(comparison)?, value: parse_number(value)? })
+                    }),
+                    _ => None,
+                };
+                match condition {
+                    Some(condition) => debugger.add_condition(condition),
+                    None => println!("Usage: when <reg> <== | != | < | <= | > | >=> <value>"),
+                }
+                None
+            },
+            "unwhen" => {
+                match args.get(1).and_then(|index| index.parse().ok()) {
+                    Some(index) if debugger.remove_condition(index) => (),
+                    _ => println!("Usage: unwhen <index>"),
+                }
+                None
+            },
+            "l" | "list" => {
+                for bp in &debugger.breakpoints {
+                    println!("  break {:04x}", bp);
+                }
+                for wp in &debugger.watchpoints {
+                    println!("  watch {:04x} {:?}", wp.addr, wp.kind);
+                }
+                for (index, condition) in debugger.conditions.iter().enumerate() {
+                    println!("  when {}: {:?}", index, condition);
+                }
+                None
+            },
+            "r" | "regs" => {
+                print_state(chip8);
+                None
+            },
+            "m" | "mem" => {
+                let start = args.get(1).and_then(|addr| parse_number(addr)).unwrap_or(chip8.i()) as usize;
+                let len = args.get(2).and_then(|len| parse_number(len)).unwrap_or(16) as usize;
+                for row in (start..start + len).step_by(16) {
+                    let bytes: Vec<String> = (row..usize::min(row + 16, start + len))
+                        .filter_map(|addr| chip8.peek(addr))
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    println!("  {:04x}: {}", row, bytes.join(" "));
+                }
+                None
+            },
+            "screen" => {
+                for y in 0..chip8.height() {
+                    let row: String = (0..chip8.width()).map(|x| if chip8.pixel(x, y) {'#'} else {'.'}).collect();
+                    println!("{}", row);
+                }
+                None
+            },
+            "h" | "help" => {
+                println!("{}", HELP);
+                None
+            },
+            "q" | "quit" => break,
+            _ => {
+                println!("Unknown command {}, `help` for a list.", args[0]);
+                None
+            },
+        };
+
+        if let Some(reason) = stop {
+            print_stop(&reason, &debugger);
+            print_location(chip8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // call 0x208; ld v6, 1; ld v6, 0; jp 0x202
+    // 0x208: ld v0, 5; ld i, 0x300; ld [i], v0; ret
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom_data(vec![
+            0x22, 0x08, 0x66, 0x01, 0x66, 0x00, 0x12, 0x02,
+            0x60, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0x00, 0xEE,
+        ]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_call() {
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        assert!(matches!(debugger.step_over(&mut chip8, 1), StopReason::Stepped));
+        assert_eq!((chip8.pc(), chip8.sp(), chip8.registers()[0]), (0x202, 0, 5));
+
+        // Anything else is a plain step
+        assert!(matches!(debugger.step_over(&mut chip8, 1), StopReason::Stepped));
+        assert_eq!(chip8.pc(), 0x204);
+    }
+
+    #[test]
+    fn step_over_stops_at_breakpoints_inside_the_call() {
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.add_breakpoint(0x20C);
+        assert!(matches!(debugger.step_over(&mut chip8, 1), StopReason::Breakpoint(0x20C)));
+        assert_eq!(chip8.sp(), 1);
+    }
+
+    #[test]
+    fn step_out_returns_to_the_caller() {
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.step(&mut chip8);
+        assert_eq!((chip8.pc(), chip8.sp()), (0x208, 1));
+
+        assert!(matches!(debugger.step_out(&mut chip8, 1), StopReason::Stepped));
+        assert_eq!((chip8.pc(), chip8.sp()), (0x202, 0));
+    }
+
+    #[test]
+    fn watchpoints_stop_on_matching_accesses() {
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.add_watchpoint(Watchpoint { addr: 0x300, kind: WatchKind::Read });
+        assert!(matches!(debugger.resume_for(&mut chip8, 1), StopReason::Paused));
+
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.add_watchpoint(Watchpoint { addr: 0x300, kind: WatchKind::Write });
+        assert!(matches!(debugger.resume_for(&mut chip8, 1), StopReason::Watchpoint { addr: 0x300, write: true }));
+        assert_eq!(chip8.pc(), 0x20E);
+    }
+
+    #[test]
+    fn conditions_only_break_when_they_become_true() {
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.add_condition(Condition { operand: Operand::V(6), comparison: Comparison::Eq, value: 1 });
+        assert!(matches!(debugger.resume_for(&mut chip8, 1), StopReason::Condition(0)));
+        assert_eq!(chip8.pc(), 0x204);
+
+        // v6 drops back to 0 and becomes 1 again on the next pass
+        assert!(matches!(debugger.resume_for(&mut chip8, 10), StopReason::Condition(0)));
+        assert_eq!(chip8.pc(), 0x204);
+
+        // True from the start, so it never becomes true
+        let (mut chip8, mut debugger) = (machine(), Debugger::new());
+        debugger.add_condition(Condition { operand: Operand::V(6), comparison: Comparison::Ge, value: 0 });
+        assert!(matches!(debugger.resume_for(&mut chip8, 10), StopReason::Paused));
+    }
+
+    #[test]
+    fn timers_tick_at_60_hz_while_stepping() {
+        // ld v0, 100; ld dt, v0; then loop forever
+        let mut chip8 = Chip8::new();
+        chip8.load_rom_data(vec![0x60, 0x64, 0xF0, 0x15, 0x12, 0x04]).unwrap();
+        chip8.set_cycles_per_second(1000);
+        let mut debugger = Debugger::new();
+        for _ in 0..1000 {
+            debugger.step(&mut chip8);
+        }
+        assert_eq!(chip8.delay_timer(), 40);
+    }
+}
@@ -0,0 +1,344 @@
+/*
+    Turns a rom back into source for assembler.rs. Code is found by following
+    jumps, calls and skips from 0x200; everything that is never reached, or
+    that the assembler has no mnemonic for, comes out as db bytes. Jump and
+    call targets get generated labels (.loc_xxx and .sub_xxx).
+
+    assemble(disassemble(rom, options), options) gives back the same bytes.
+*/
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::assembler::Options;
+
+const START: usize = 0x200;
+
+// Longest run of db bytes on one line
+const BYTES_PER_LINE: usize = 8;
+
+pub fn disassemble(rom: &[u8], options: &Options) -> String {
+    let code = find_code(rom);
+    let labels = find_labels(rom, &code);
+
+    let label_for = |addr: u16| -> String {
+        match labels.get(&(addr as usize)) {
+            Some(label) => label.clone(),
+            None => addr.to_string(),
+        }
+    };
+
+    let mut out = String::new();
+    let mut data: Vec<u8> = vec![];
+    let mut data_start = START;
+    let mut pos = 0;
+
+    while pos < rom.len() {
+        let addr = START + pos;
+        // An instruction with a jump target in its second byte has to be
+        // split into db bytes so the label has somewhere to go
+        let instruction = if code.contains(&pos) && pos + 1 < rom.len() && !labels.contains_key(&(addr + 1)) {
+            let opcode = ((rom[pos] as u16) << 8) | rom[pos + 1] as u16;
+            instruction(opcode, options, &label_for).map(|text| (text, opcode))
+        } else {None};
+
+        // Data runs are broken up at labels and before real instructions
+        if !data.is_empty() && (instruction.is_some() || labels.contains_key(&addr) || data.len() == BYTES_PER_LINE) {
+            emit_data(&mut out, data_start, &data);
+            data.clear();
+        }
+
+        if let Some(label) = labels.get(&addr) {
+            out.push_str(&format!("{}\n", label));
+        }
+
+        match instruction {
+            Some((text, opcode)) => {
+                out.push_str(&format!("    {:<23} ; {:03x}: {:04x}\n", text, addr, opcode));
+                pos += 2;
+            },
+            None => {
+                if data.is_empty() {
+                    data_start = addr;
+                }
+                data.push(rom[pos]);
+                pos += 1;
+            },
+        }
+    }
+
+    if !data.is_empty() {
+        emit_data(&mut out, data_start, &data);
+    }
+
+    out
+}
+
+fn emit_data(out: &mut String, addr: usize, data: &[u8]) {
+    let bytes: Vec<String> = data.iter().map(|byte| byte.to_string()).collect();
+    out.push_str(&format!("    {:<23} ; {:03x}\n", format!("db {}", bytes.join(", ")), addr));
+}
+
+// Offsets into the rom of every reachable instruction
+fn find_code(rom: &[u8]) -> HashSet<usize> {
+    let mut code: HashSet<usize> = HashSet::new();
+    let mut pending: Vec<usize> = vec![0];
+
+    while let Some(pos) = pending.pop() {
+        if pos + 1 >= rom.len() || code.contains(&pos) {
+            continue;
+        }
+        code.insert(pos);
+
+        let opcode = ((rom[pos] as u16) << 8) | rom[pos + 1] as u16;
+        let target = ((opcode & 0xFFF) as usize).wrapping_sub(START);
+
+        match opcode >> 12 {
+            0x0 if opcode == 0x00EE || opcode == 0x00FD => (),
+            0x1 => pending.push(target),
+            0x2 => {
+                pending.push(target);
+                pending.push(pos + 2);
+            },
+            // Skips can land on either of the next two instructions
+            0x3 | 0x4 | 0x5 | 0x9 => {
+                pending.push(pos + 2);
+                pending.push(pos + 4);
+            },
+            0xE if opcode & 0xFF == 0x9E || opcode & 0xFF == 0xA1 => {
+                pending.push(pos + 2);
+                pending.push(pos + 4);
+            },
+            // Jump through V0, there's no telling where it goes
+            0xB => (),
+            // XO-CHIP F000 nnnn takes up two words
+            0xF if opcode == 0xF000 => pending.push(pos + 4),
+            _ => pending.push(pos + 2),
+        }
+    }
+
+    code
+}
+
+fn find_labels(rom: &[u8], code: &HashSet<usize>) -> BTreeMap<usize, String> {
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+
+    for pos in code {
+        let opcode = ((rom[*pos] as u16) << 8) | rom[*pos + 1] as u16;
+        let target = (opcode & 0xFFF) as usize;
+        if target < START || target >= START + rom.len() {
+            continue;
+        }
+
+        match opcode >> 12 {
+            0x1 => {labels.entry(target).or_insert(format!(".loc_{:03x}", target));},
+            0x2 => {labels.insert(target, format!(".sub_{:03x}", target));},
+            _ => (),
+        }
+    }
+
+    labels
+}
+
+// Source for one opcode, or None if the assembler can't express it
+fn instruction(opcode: u16, options: &Options, label_for: &dyn Fn(u16) -> String) -> Option<String> {
+    let nnn = opcode & 0xFFF;
+    let n = opcode & 0xF;
+    let x = (opcode & 0xF00) >> 8;
+    let y = (opcode & 0xF0) >> 4;
+    let kk = opcode & 0xFF;
+
+    let text = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00E0 => "cls".to_string(),
+            0x00EE => "ret".to_string(),
+            _ => format!("sys {}", nnn),
+        },
+        0x1 => format!("jp {}", label_for(nnn)),
+        0x2 => format!("call {}", label_for(nnn)),
+        0x3 => format!("se v{}, {}", x, kk),
+        0x4 => format!("sne v{}, {}", x, kk),
+        0x5 if n == 0 => format!("se v{}, v{}", x, y),
+        0x6 => format!("ld v{}, {}", x, kk),
+        0x7 => format!("add v{}, {}", x, kk),
+        0x8 => match n {
+            0x0 => format!("ld v{}, v{}", x, y),
+            0x4 => format!("add v{}, v{}", x, y),
+            0x5 => format!("sub v{}, v{}", x, y),
+            0x1 => format!("or v{}, v{}", x, y),
+            0x2 => format!("and v{}, v{}", x, y),
+            0x3 => format!("xor v{}, v{}", x, y),
+            0x7 => format!("subn v{}, v{}", x, y),
+            0x6 if y == 0 => format!("shr v{}", x),
+            0xE if y == 0 => format!("shl v{}", x),
+            0x6 => format!("shr v{}, v{}", x, y),
+            0xE => format!("shl v{}, v{}", x, y),
+            _ => return None,
+        },
+        0x9 => match n {
+            0x0 => format!("sne v{}, v{}", x, y),
+            0x1 if options.comparisons => format!("gt v{}, v{}", x, y),
+            0x2 if options.comparisons => format!("gte v{}, v{}", x, y),
+            0x3 if options.comparisons => format!("lt v{}, v{}", x, y),
+            0x4 if options.comparisons => format!("lte v{}, v{}", x, y),
+            _ => return None,
+        },
+        0xA => format!("ldi {}", nnn),
+        0xB => format!("jp v0, {}", label_for(nnn)),
+        0xC => format!("rnd v{}, {}", x, kk),
+        0xD => format!("drw v{}, v{}, {}", x, y, n),
+        0xE => match kk {
+            0x9E => format!("skp v{}", x),
+            0xA1 => format!("sknp v{}", x),
+            _ => return None,
+        },
+        0xF => match kk {
+            0x07 => format!("ld v{}, dt", x),
+            0x0A => format!("getkey v{}", x),
+            0x15 => format!("ld dt, v{}", x),
+            0x18 => format!("ld st, v{}", x),
+            0x1E => format!("addi v{}", x),
+            0x29 => format!("ldsprt v{}", x),
+            0x33 => format!("ldbcd v{}", x),
+            0x55 => format!("dumpreg v{}", x),
+            0x65 => format!("ldreg v{}", x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+// Cowgod style mnemonic for any opcode this emulator runs, for traces and
+// debugger output. Unlike `instruction` this always produces something, but
+// the result isn't necessarily assembler syntax.
+pub fn mnemonic(opcode: u16) -> String {
+    let nnn = opcode & 0xFFF;
+    let n = opcode & 0xF;
+    let x = (opcode & 0xF00) >> 8;
+    let y = (opcode & 0xF0) >> 4;
+    let kk = opcode & 0xFF;
+
+    match opcode >> 12 {
+        0x0 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FB => "SCR".to_string(),
+            0x00FC => "SCL".to_string(),
+            0x00FD => "EXIT".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ if opcode & 0xFFF0 == 0x00C0 => format!("SCD {}", n),
+            _ if opcode & 0xFFF0 == 0x00D0 => format!("SCU {}", n),
+            _ => format!("SYS {:03X}", nnn),
+        },
+        0x1 => format!("JP {:03X}", nnn),
+        0x2 => format!("CALL {:03X}", nnn),
+        0x3 => format!("SE V{:X}, {:02X}", x, kk),
+        0x4 => format!("SNE V{:X}, {:02X}", x, kk),
+        0x5 => match n {
+            0x0 => format!("SE V{:X}, V{:X}", x, y),
+            0x2 => format!("SAVE V{:X}-V{:X}", x, y),
+            0x3 => format!("LOAD V{:X}-V{:X}", x, y),
+            _ => format!("??? {:04X}", opcode),
+        },
+        0x6 => format!("LD V{:X}, {:02X}", x, kk),
+        0x7 => format!("ADD V{:X}, {:02X}", x, kk),
+        0x8 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("??? {:04X}", opcode),
+        },
+        0x9 => match n {
+            0x0 => format!("SNE V{:X}, V{:X}", x, y),
+            0x1 => format!("GT V{:X}, V{:X}", x, y),
+            0x2 => format!("GTE V{:X}, V{:X}", x, y),
+            0x3 => format!("LT V{:X}, V{:X}", x, y),
+            0x4 => format!("LTE V{:X}, V{:X}", x, y),
+            _ => format!("??? {:04X}", opcode),
+        },
+        0xA => format!("LD I, {:03X}", nnn),
+        0xB => format!("JP V0, {:03X}", nnn),
+        0xC => format!("RND V{:X}, {:02X}", x, kk),
+        0xD => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE => match kk {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("??? {:04X}", opcode),
+        },
+        _ => match kk {
+            0x00 if x == 0 => "LD I, LONG".to_string(),
+            0x01 => format!("PLANE {}", x),
+            0x02 if x == 0 => "AUDIO".to_string(),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3A => format!("PITCH V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => format!("??? {:04X}", opcode),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::fs;
+
+    fn roundtrip(rom: &[u8], options: &Options) {
+        let source = disassemble(rom, options);
+        let assembled = assemble(&source, "roundtrip.asm", options)
+            .unwrap_or_else(|errors| panic!("{}\n\n{}", errors[0], source));
+        assert_eq!(assembled, rom, "\n{}", source);
+    }
+
+    fn bundled_roms() -> Vec<Vec<u8>> {
+        let root = env!("CARGO_MANIFEST_DIR");
+        let mut paths = vec![format!("{}/c8_test.c8", root), format!("{}/IBM Logo.c8", root)];
+        for entry in fs::read_dir(format!("{}/roms", root)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "ch8") {
+                paths.push(path.to_string_lossy().to_string());
+            }
+        }
+        paths.iter().map(|path| fs::read(path).unwrap()).collect()
+    }
+
+    #[test]
+    fn bundled_roms_roundtrip() {
+        for comparisons in [false, true] {
+            for rom in bundled_roms() {
+                roundtrip(&rom, &Options { comparisons });
+            }
+        }
+    }
+
+    #[test]
+    fn random_roms_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for comparisons in [false, true] {
+            for _ in 0..200 {
+                let len = rng.gen_range(0..512);
+                let rom: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+                roundtrip(&rom, &Options { comparisons });
+            }
+        }
+    }
+}
@@ -1,20 +1,50 @@
-use std::{fs::{File, self}, io::Read, collections::HashMap};
-use crate::input::*;
-use rand::Rng;
+use std::{fs, collections::HashMap, time::Instant};
 
-use rgraphics::{textures::RenderTexture2D, Program, colors};
+use crate::backend::{Display, Keypad, Beeper, NullDisplay, NullBeeper, SharedKeypad};
+use crate::error::Chip8Error;
+use crate::quirks::{Quirks, Platform};
+use crate::random::Random;
+use crate::state::Snapshot;
+use crate::trace::Tracer;
 
-const WIDTH: u8 = 64;
-const HEIGHT: u8 = 32;
+// Low resolution screen, the only one on plain CHIP-8
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+// SUPER-CHIP high resolution screen
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+// Delay and sound timers count down at this rate no matter the cpu speed
+pub const TIMER_HZ: u32 = 60;
+
+const PROGRAM_START: usize = 0x200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: usize,
+    pub write: bool,
+}
+
+// The 10 byte tall SUPER-CHIP font is stored right after the small one
+const BIG_FONT_START: u16 = 0x50;
 
 pub struct Chip8 {
     // 0x0 to 0x1FF reserved&
     // 0x200 (program start)
-    // 0xFFF end
-    memory: [u8; 4096],
+    // 0xFFF end, or 0xFFFF for XO-CHIP
+    memory: Vec<u8>,
 
     cps: u16,
 
+    // Fraction of an instruction left over from the last frame, so cycle
+    // rates that aren't a multiple of 60 still average out
+    cycle_remainder: f64,
+
+    // Wall clock time not yet spent on frames, used by clock()
+    frame_time: f64,
+    last_clock: Option<Instant>,
+
     // Stack is an array of 16 16-bit values
     stack: [u16; 16],
 
@@ -36,10 +66,56 @@ pub struct Chip8 {
     // Stack pointer
     sp: u8,
 
-    display: RenderTexture2D,
+    // One byte per pixel, row major. Each bit is one plane, 0 is off and
+    // 1 is on. Sized for the current resolution.
+    display: Vec<u8>,
+    hires: bool,
+
+    // Planes drawn to, cleared and scrolled. Always 1 outside of XO-CHIP.
+    planes: u8,
+
+    // XO-CHIP audio pattern (F002) and pitch (Fx3A). Until a pattern is
+    // loaded the normal beep plays.
+    pattern: Option<[u8; 16]>,
+    pitch: u8,
+
+    // Set by 00FD, nothing runs after that
+    halted: bool,
+
+    // SUPER-CHIP RPL user flags, Fx75/Fx85
+    rpl: [u8; 16],
+
+    // Key state as of the last poll of the keypad
+    keys: [bool; 16],
+
+    // Last key pressed, consumed by Fx0A
+    last_pressed: Option<u8>,
+    waiting_for_key: bool,
 
-    key_map: HashMap<u8, Key>,
     sprite_locations: HashMap<u8, u16>,
+
+    quirks: Quirks,
+    platform: Platform,
+
+    // Non-standard 9xy1 - 9xy4 comparisons (assembler gt, gte, lt, lte)
+    comparisons: bool,
+
+    // Source of Cxkk random numbers
+    random: Random,
+
+    // Instructions executed since power on
+    cycles: u64,
+    tracer: Option<Tracer>,
+
+    // Data reads and writes made by the last instruction, for watchpoints.
+    // Only collected when track_accesses is on.
+    accesses: Vec<MemoryAccess>,
+    track_accesses: bool,
+
+    display_out: Box<dyn Display>,
+    keypad: Box<dyn Keypad>,
+    beeper: Box<dyn Beeper>,
+    beeping: bool,
 }
 
 impl Chip8 {
@@ -64,33 +140,44 @@ impl Chip8 {
             0xF0, 0x80, 0xF0, 0x80, 0x80,
         ];
 
-        for i in 0..sprites.len() {
-            self.memory[i] = sprites[i];
-        }
-    }
+        self.memory[..sprites.len()].copy_from_slice(&sprites);
+
+        let big_sprites = vec![
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF,
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF,
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03,
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C,
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0,
+        ];
 
+        let start = BIG_FONT_START as usize;
+        self.memory[start..start + big_sprites.len()].copy_from_slice(&big_sprites);
+    }
 
-    pub fn new(program: &Program) -> Chip8 {
-        let mut key_map: HashMap<u8, Key> = HashMap::new();
 
-        key_map.insert(0, Key::Key0);
-        key_map.insert(1, Key::Key1);
-        key_map.insert(2, Key::Key2);
-        key_map.insert(3, Key::Key3);
-        key_map.insert(4, Key::Key4);
-        key_map.insert(5, Key::Key5);
-        key_map.insert(6, Key::Key6);
-        key_map.insert(7, Key::Key7);
-        key_map.insert(8, Key::Key8);
-        key_map.insert(9, Key::Key9);
-        key_map.insert(10, Key::A);
-        key_map.insert(11, Key::B);
-        key_map.insert(12, Key::C);
-        key_map.insert(13, Key::D);
-        key_map.insert(14, Key::E);
-        key_map.insert(15, Key::F);
+    pub fn new() -> Chip8 {
+        Chip8::with_quirks(Quirks::default())
+    }
 
+    // Emulator for `platform`, using the quirks its ROMs usually expect
+    pub fn for_platform(platform: Platform) -> Chip8 {
+        let mut c8 = Chip8::with_quirks(platform.default_quirks());
+        c8.set_platform(platform);
+        c8
+    }
 
+    pub fn with_quirks(quirks: Quirks) -> Chip8 {
         let mut loc_map: HashMap<u8, u16> = HashMap::new();
 
         for i in 0..16 {
@@ -98,111 +185,534 @@ impl Chip8 {
         }
 
         let mut c8 = Chip8 {
-            memory: [0; 4096],
+            memory: vec![0; Platform::Chip8.memory_size()],
             stack: [0; 16],
             registers: [0; 16],
             cps: 500,
+            cycle_remainder: 0.0,
+            frame_time: 0.0,
+            last_clock: None,
             i: 0,
             dt: 0,
             st: 0,
-            pc: 0x200,
+            pc: PROGRAM_START as u16,
             sp: 0,
-            display: RenderTexture2D::new(&program.renderer, WIDTH as u32, HEIGHT as u32),
-            key_map,
+            display: vec![0; WIDTH * HEIGHT],
+            hires: false,
+            planes: 1,
+            pattern: None,
+            pitch: 64,
+            halted: false,
+            rpl: [0; 16],
+            keys: [false; 16],
+            last_pressed: None,
+            waiting_for_key: false,
             sprite_locations: loc_map,
+            quirks,
+            platform: Platform::Chip8,
+            comparisons: false,
+            random: Random::from_entropy(),
+            cycles: 0,
+            tracer: None,
+            accesses: vec![],
+            track_accesses: false,
+            display_out: Box::new(NullDisplay),
+            keypad: Box::new(SharedKeypad::new()),
+            beeper: Box::new(NullBeeper),
+            beeping: false,
         };
 
         c8.load_sprites();
         c8
     }
 
-    pub fn draw(&mut self, program: &mut Program) {
-        self.display.apply(&program.renderer);
-        program.draw_texture(-1.0, 1.0, 2.0, 2.0, &self.display);
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.display
+    }
+
+    // Current screen size, which changes when SUPER-CHIP switches resolution
+    pub fn width(&self) -> usize {
+        if self.hires {HIRES_WIDTH} else {WIDTH}
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {HIRES_HEIGHT} else {HEIGHT}
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.display[y * self.width() + x] != 0
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.memory.resize(platform.memory_size(), 0);
+    }
+
+    // Turn on the 9xy1 - 9xy4 comparison opcodes. They set VF to vx > vy,
+    // vx >= vy, vx < vy and vx <= vy. Without this they are invalid.
+    pub fn set_comparisons(&mut self, enabled: bool) {
+        self.comparisons = enabled;
+    }
+
+    pub fn set_display(&mut self, display: Box<dyn Display>) {
+        self.display_out = display;
+    }
+
+    pub fn set_keypad(&mut self, keypad: Box<dyn Keypad>) {
+        self.keypad = keypad;
+    }
+
+    pub fn set_beeper(&mut self, beeper: Box<dyn Beeper>) {
+        self.beeper = beeper;
+    }
+
+    fn poll_keypad(&mut self) {
+        for key in 0..16 {
+            let down = self.keypad.is_key_down(key);
+            if down && !self.keys[key as usize] {
+                self.last_pressed = Some(key);
+            }
+            self.keys[key as usize] = down;
+        }
+    }
+
+    fn is_key_down(&self, key: u8) -> bool {
+        self.keys[(key & 0xF) as usize]
+    }
+
+    fn update_beeper(&mut self) {
+        let beeping = self.st > 0;
+        if beeping != self.beeping {
+            self.beeping = beeping;
+            self.beeper.set_beeping(beeping);
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            platform: self.platform,
+            memory: self.memory.clone(),
+            registers: self.registers,
+            stack: self.stack,
+            sp: self.sp,
+            pc: self.pc,
+            i: self.i,
+            dt: self.dt,
+            st: self.st,
+            display: self.display.clone(),
+            hires: self.hires,
+            planes: self.planes,
+            pattern: self.pattern,
+            pitch: self.pitch,
+            halted: self.halted,
+            rpl: self.rpl,
+            waiting_for_key: self.waiting_for_key,
+            rng_seed: self.random.seed(),
+            rng_draws: self.random.draws(),
+            rng_position: self.random.position(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.platform = snapshot.platform;
+        self.memory = snapshot.memory;
+        self.registers = snapshot.registers;
+        self.stack = snapshot.stack;
+        self.sp = snapshot.sp;
+        self.pc = snapshot.pc;
+        self.i = snapshot.i;
+        self.dt = snapshot.dt;
+        self.st = snapshot.st;
+        self.display = snapshot.display;
+        self.hires = snapshot.hires;
+        self.planes = snapshot.planes;
+        self.pattern = snapshot.pattern;
+        self.pitch = snapshot.pitch;
+        self.halted = snapshot.halted;
+        self.rpl = snapshot.rpl;
+        self.waiting_for_key = snapshot.waiting_for_key;
+        self.random.restore(snapshot.rng_seed, snapshot.rng_draws, snapshot.rng_position);
+        self.last_pressed = None;
+
+        // Saves from before any F002 have no pattern, and shouldn't keep
+        // playing the current one
+        self.beeper.set_pattern(self.pattern, self.pattern_rate());
+        self.update_beeper();
+        self.present();
+    }
+
+    // Versioned binary save state, see Snapshot::encode for the layout
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().encode()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Chip8Error> {
+        let snapshot = Snapshot::decode(data)?;
+        if snapshot.display.len() != if snapshot.hires {HIRES_WIDTH * HIRES_HEIGHT} else {WIDTH * HEIGHT} {
+            return Err(Chip8Error::BadSaveState("display size does not match resolution"));
+        }
+        self.restore(snapshot);
+        Ok(())
+    }
+
+    pub fn load_rom_from_file(&mut self, path: &str) -> Result<(), Chip8Error> {
+        let buf = fs::read(path)?;
+        self.load_rom_data(buf)
+    }
+
+    pub fn load_rom_data(&mut self, data: Vec<u8>) -> Result<(), Chip8Error> {
+        let max = self.memory.len() - PROGRAM_START;
+        if data.len() > max {
+            return Err(Chip8Error::RomTooLarge { size: data.len(), max });
+        }
+
+        self.memory[PROGRAM_START..PROGRAM_START + data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    // Instruction fetches. Not reported as memory accesses.
+    fn fetch(&self, addr: usize) -> Result<u8, Chip8Error> {
+        match self.memory.get(addr) {
+            Some(byte) => Ok(*byte),
+            None => Err(Chip8Error::MemoryOutOfBounds { access: addr, address: self.pc }),
+        }
     }
 
-    pub fn load_rom_from_file(&mut self, path: &str) {
-        let mut f = File::open(&path).expect(format!("No file {}", path).as_str()); 
-        let metadata = fs::metadata(&path).expect("Unable to read file metadata.");
-        let mut buf = vec![0; metadata.len() as usize];
-        f.read(&mut buf).expect("Buffer overflow.");
-        self.load_rom_data(buf);
-        
+    fn read(&mut self, addr: usize) -> Result<u8, Chip8Error> {
+        if self.track_accesses {
+            self.accesses.push(MemoryAccess { addr, write: false });
+        }
+        self.fetch(addr)
     }
 
-    pub fn load_rom_data(&mut self, data: Vec<u8>) {
-        for i in 0..data.len() {
-            self.memory[0x200 + i] = data[i];
+    fn write(&mut self, addr: usize, value: u8) -> Result<(), Chip8Error> {
+        if self.track_accesses {
+            self.accesses.push(MemoryAccess { addr, write: true });
+        }
+        match self.memory.get_mut(addr) {
+            Some(byte) => {*byte = value; Ok(())},
+            None => Err(Chip8Error::MemoryOutOfBounds { access: addr, address: self.pc }),
         }
     }
 
+    // Memory the last instruction read or wrote, if tracking is on
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    pub fn set_access_tracking(&mut self, enabled: bool) {
+        self.track_accesses = enabled;
+        self.accesses.clear();
+    }
+
+    // Read memory without it counting as an access. None past the end.
+    pub fn peek(&self, addr: usize) -> Option<u8> {
+        self.memory.get(addr).copied()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    // Return addresses, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    // Opcode at the program counter, what run_single will execute next
+    pub fn next_opcode(&self) -> Option<u16> {
+        Some(((self.peek(self.pc as usize)? as u16) << 8) | self.peek(self.pc as usize + 1)? as u16)
+    }
+
+    pub fn cycles_per_second(&self) -> u16 {
+        self.cps
+    }
+
+    // Restart the Cxkk random sequence from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random.set_seed(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.random.seed()
+    }
+
+    // Recording keeps every random byte Cxkk uses, for replay_random later
+    pub fn start_random_recording(&mut self) {
+        self.random.start_recording();
+    }
+
+    pub fn stop_random_recording(&mut self) -> Vec<u8> {
+        self.random.stop_recording()
+    }
+
+    // Feed Cxkk a recorded stream before falling back to the seed
+    pub fn replay_random(&mut self, stream: Vec<u8>) {
+        self.random.replay(stream);
+    }
+
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    // Log every instruction from now on. None turns tracing off.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
     pub fn set_cycles_per_second(&mut self, cycles: u16) {
         self.cps = cycles;
     }
 
+    fn present(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        self.display_out.draw(&self.display, width, height);
+    }
+
+    // Only clears the selected planes
     fn clear_display(&mut self) {
-        for height in 0..self.display.dimensions.1 {
-            for width in 0..self.display.dimensions.0 {
-                self.display.set_pixel(width, height, colors::BLACK);
+        let planes = self.planes;
+        for pixel in self.display.iter_mut() {
+            *pixel &= !planes;
+        }
+        self.present();
+    }
+
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.display = vec![0; self.width() * self.height()];
+        self.present();
+    }
+
+    // Move the selected planes by dx, dy pixels. Whatever scrolls in is blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let planes = self.planes;
+        let mut scrolled: Vec<u8> = self.display.iter().map(|pixel| pixel & !planes).collect();
+
+        for y in 0..height {
+            for x in 0..width {
+                let (fromx, fromy) = (x - dx, y - dy);
+                if fromx < 0 || fromx >= width || fromy < 0 || fromy >= height {
+                    continue;
+                }
+                scrolled[(y * width + x) as usize] |= self.display[(fromy * width + fromx) as usize] & planes;
             }
         }
+
+        self.display = scrolled;
+        self.present();
+    }
+
+    // XO-CHIP skips have to jump over the whole 4 byte F000 nnnn
+    fn skip(&mut self) -> Result<(), Chip8Error> {
+        let next = self.pc as usize + 2;
+        if self.platform == Platform::XoChip && self.fetch(next)? == 0xF0 && self.fetch(next + 1)? == 0x00 {
+            self.advance(4)
+        } else {
+            self.advance(2)
+        }
+    }
+
+    // Move PC forward. Running off the end of 64 KiB XO-CHIP memory is an
+    // error, like running off the end of 4K is when the next fetch fails.
+    fn advance(&mut self, bytes: u16) -> Result<(), Chip8Error> {
+        match self.pc.checked_add(bytes) {
+            Some(pc) => {
+                self.pc = pc;
+                Ok(())
+            },
+            None => Err(Chip8Error::MemoryOutOfBounds { access: self.pc as usize + bytes as usize, address: self.pc }),
+        }
+    }
+
+    // Pattern playback rate in bits per second, from the XO-CHIP pitch register
+    fn pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
     }
 
-    fn draw_sprite(&mut self, sprite: &Vec<u8>, x: u8, y: u8) {
+    // Rows are `sprite_width` bits wide (8, or 16 for SUPER-CHIP big sprites).
+    // Drawn to the single plane in `plane`.
+    fn draw_sprite(&mut self, sprite: &[u16], sprite_width: usize, plane: u8, x: u8, y: u8) -> bool {
         let mut collision = false; 
+        let (screen_width, screen_height) = (self.width(), self.height());
 
-        for height in 0..sprite.len() {
-            for width in 0..8 {
-                let num = (sprite[height] >> (7 - width)) & 0x1;
+        for (height, row) in sprite.iter().enumerate() {
+            for width in 0..sprite_width {
+                let num = (row >> (sprite_width - 1 - width)) & 0x1;
                 if num == 0 {
                     continue;
                 }
 
-                let (locx, locy) = (
-                    (u8::wrapping_add(x, width)) % WIDTH,
-                    (u8::wrapping_add(y, height as u8)) % HEIGHT,
+                let (mut locx, mut locy) = (
+                    x as usize % screen_width + width,
+                    y as usize % screen_height + height,
                 );
 
-                if self.display.get_pixel(locx as u32, locy as u32).approx_equal(&colors::WHITE) {
-                    self.display.set_pixel(locx as u32, locy as u32, colors::BLACK);
-                    collision = true;
+                if locx >= screen_width || locy >= screen_height {
+                    if self.quirks.clip_sprites {continue;}
+                    locx %= screen_width;
+                    locy %= screen_height;
                 }
 
-                else {
-                    self.display.set_pixel(locx as u32, locy as u32, colors::WHITE);
+                let pixel = &mut self.display[locy * screen_width + locx];
+                if *pixel & plane != 0 {
+                    collision = true;
                 }
+                *pixel ^= plane;
             }
         }
 
-        self.registers[15] = if collision {1} else {0}
+        collision
     }
 
-    pub fn clock(&mut self, program: &mut Program) {
-        let start = std::time::Instant::now();
-        self.run_single(program);
-        while (std::time::Instant::now() - start).as_secs_f64() < (1.0 / self.cps as f64) {
-            
+    // Count both timers down by one. Called at TIMER_HZ by run_frame and step.
+    pub fn tick_timers(&mut self) {
+        // The beeper renders the frame that just ran, so ST = n sounds for
+        // n ticks
+        self.beeper.tick();
+
+        if self.dt > 0 {
+            self.dt -= 1;
+        }
+
+        if self.st > 0 {
+            self.st -= 1;
         }
-        self.draw(program);
+
+        self.update_beeper();
     }
 
-    fn run_single(&mut self, program: &mut Program) {
+    // Run one 60 Hz frame: cps / 60 instructions, then a timer tick. After
+    // some step()s this finishes the frame they started.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        if self.cycle_remainder < 1.0 {
+            self.cycle_remainder += self.cps as f64 / TIMER_HZ as f64;
+        }
+        let cycles = self.cycle_remainder as u32;
+        self.cycle_remainder -= cycles as f64;
+
+        for _ in 0..cycles {
+            self.run_single()?;
+        }
+
+        self.tick_timers();
+        Ok(())
+    }
+
+    // Run a single instruction, ticking the timers if it ends a frame. Shares
+    // run_frame's bookkeeping, so stepping keeps the same 60 Hz pace.
+    // Returns whether the timers ticked.
+    pub fn step(&mut self) -> Result<bool, Chip8Error> {
+        let mut ticked = false;
+        while self.cycle_remainder < 1.0 {
+            self.cycle_remainder += u16::max(self.cps, 1) as f64 / TIMER_HZ as f64;
+            // Below 60 cps some frames have no instructions at all
+            if self.cycle_remainder < 1.0 {
+                self.tick_timers();
+                ticked = true;
+            }
+        }
+
+        self.run_single()?;
+        self.cycle_remainder -= 1.0;
+        if self.cycle_remainder < 1.0 {
+            self.tick_timers();
+            ticked = true;
+        }
+        Ok(ticked)
+    }
+
+    // Real time driver for frontends. Runs however many frames have passed
+    // on the wall clock since the last call.
+    pub fn clock(&mut self) -> Result<(), Chip8Error> {
+        let now = Instant::now();
+        if let Some(last) = self.last_clock {
+            // Don't try to catch up after long stalls (window drags, breakpoints)
+            self.frame_time = f64::min(self.frame_time + (now - last).as_secs_f64(), 0.25);
+        }
+        self.last_clock = Some(now);
+
+        let frame_length = 1.0 / TIMER_HZ as f64;
+        while self.frame_time >= frame_length {
+            self.frame_time -= frame_length;
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+
+    // Forget wall clock time so the next clock() doesn't try to catch up on
+    // time spent paused or rewinding
+    pub fn reset_clock(&mut self) {
+        self.last_clock = None;
+        self.frame_time = 0.0;
+    }
+
+    pub fn run_single(&mut self) -> Result<(), Chip8Error> {
         // nnn or addr: lowest 12 bits
         // n or nibble: lowest 4 bits
         // x: lower 4 bits of high byte
         // y: upper 4 bits of low byte
         // kk or byte: lowest 8 bits
 
-        let instruction: u16 = ((self.memory[self.pc as usize] as u16) << 8) | (self.memory[(self.pc + 1) as usize] as u16);
+        if self.halted {
+            return Ok(());
+        }
+
+        self.accesses.clear();
+        let instruction: u16 = ((self.fetch(self.pc as usize)? as u16) << 8) | (self.fetch(self.pc as usize + 1)? as u16);
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.log(self, self.cycles, instruction);
+            self.tracer = Some(tracer);
+        }
+        self.cycles += 1;
+
         let nnn = instruction & 0xFFF;
         let n = instruction & 0xF;
         let x = (instruction & 0xF00) >> 8;
         let y = (instruction & 0xF0) >> 4;
         let kk = instruction & 0xFF;
 
-        if self.dt > 0 {
-            self.dt -= 1;
-        } else {self.dt = 0;}
+        let schip = self.platform != Platform::Chip8;
+        let xo = self.platform == Platform::XoChip;
+
+        self.poll_keypad();
 
         //println!("{:#06x}", instruction);
 
@@ -211,31 +721,60 @@ impl Chip8 {
                 match instruction {
                     0x00E0 => self.clear_display(),
                     0x00EE => {
-                        self.pc = self.stack[self.sp as usize];
+                        if self.sp == 0 {
+                            return Err(Chip8Error::StackUnderflow { address: self.pc });
+                        }
                         self.sp -= 1;
+                        self.pc = self.stack[self.sp as usize];
+                    },
+                    0x00FB if schip => self.scroll(4, 0),
+                    0x00FC if schip => self.scroll(-4, 0),
+                    0x00FD if schip => {
+                        self.halted = true;
+                        return Ok(());
                     },
+                    0x00FE if schip => self.set_hires(false),
+                    0x00FF if schip => self.set_hires(true),
+                    _ if schip && instruction & 0xFFF0 == 0x00C0 => self.scroll(0, n as isize),
+                    _ if xo && instruction & 0xFFF0 == 0x00D0 => self.scroll(0, -(n as isize)),
+                    // 0nnn (SYS) calls machine code on the original hardware, ignored
                     _ => (),
                 }
             },
 
             1 => {
                 self.pc = nnn;
-                return;
+                return Ok(());
             },
             2 => {
-                self.sp += 1;
+                if self.sp as usize >= self.stack.len() {
+                    return Err(Chip8Error::StackOverflow { address: self.pc });
+                }
                 self.stack[self.sp as usize] = self.pc;
+                self.sp += 1;
                 self.pc = nnn;
-                return;
-            },
-            3 => {
-                if self.registers[x as usize] == kk as u8 {self.pc += 2;}
-            },
-            4 => {
-                if self.registers[x as usize] != kk as u8 {self.pc += 2;}
+                return Ok(());
             },
+            3 if self.registers[x as usize] == kk as u8 => self.skip()?,
+            4 if self.registers[x as usize] != kk as u8 => self.skip()?,
             5 => {
-                if self.registers[x as usize] == self.registers[y as usize] {self.pc += 2;}
+                match n {
+                    0 => {if self.registers[x as usize] == self.registers[y as usize] {self.skip()?;}},
+                    // Save and load vx through vy, in either direction, without touching I
+                    2 if xo => {
+                        let regs = if x <= y {(x..=y).collect::<Vec<u16>>()} else {(y..=x).rev().collect()};
+                        for (offset, reg) in regs.iter().enumerate() {
+                            self.write(self.i as usize + offset, self.registers[*reg as usize])?;
+                        }
+                    },
+                    3 if xo => {
+                        let regs = if x <= y {(x..=y).collect::<Vec<u16>>()} else {(y..=x).rev().collect()};
+                        for (offset, reg) in regs.iter().enumerate() {
+                            self.registers[*reg as usize] = self.read(self.i as usize + offset)?;
+                        }
+                    },
+                    _ => return Err(Chip8Error::InvalidOpcode { opcode: instruction, address: self.pc }),
+                }
             },
             6 => {
                 self.registers[x as usize] = kk as u8;
@@ -246,9 +785,18 @@ impl Chip8 {
             8 => {
                 match n {
                     0 => {self.registers[x as usize] = self.registers[y as usize]},
-                    1 => {self.registers[x as usize] |= self.registers[y as usize]},
-                    2 => {self.registers[x as usize] &= self.registers[y as usize]},
-                    3 => {self.registers[x as usize] ^= self.registers[y as usize]},
+                    1 => {
+                        self.registers[x as usize] |= self.registers[y as usize];
+                        if self.quirks.logic_resets_vf {self.registers[15] = 0}
+                    },
+                    2 => {
+                        self.registers[x as usize] &= self.registers[y as usize];
+                        if self.quirks.logic_resets_vf {self.registers[15] = 0}
+                    },
+                    3 => {
+                        self.registers[x as usize] ^= self.registers[y as usize];
+                        if self.quirks.logic_resets_vf {self.registers[15] = 0}
+                    },
                     4 => {
                         if self.registers[x as usize] as u16 + self.registers[y as usize] as u16 > 255 {self.registers[15] = 1} else {self.registers[15] = 0};
 
@@ -260,6 +808,7 @@ impl Chip8 {
                         self.registers[x as usize] = u8::wrapping_sub(n1, n2);
                     },
                     6 => {
+                        if self.quirks.shift_uses_vy {self.registers[x as usize] = self.registers[y as usize]}
                         if self.registers[x as usize] & 0x1 == 1 {self.registers[15] = 1;} else {self.registers[15] = 0;}
                         self.registers[x as usize] = u8::wrapping_div(self.registers[x as usize], 2);
                     },
@@ -269,76 +818,128 @@ impl Chip8 {
                         self.registers[x as usize] = u8::wrapping_sub(n2, n1);
                     },
                     0xE => {
+                        if self.quirks.shift_uses_vy {self.registers[x as usize] = self.registers[y as usize]}
                         if self.registers[x as usize] >> 7 == 1 {self.registers[15] = 1} else {self.registers[15] = 0};
                         self.registers[x as usize] = u8::wrapping_mul(self.registers[x as usize], 2);
                     },
-                    _ => panic!("Unknown instruction"),
+                    _ => return Err(Chip8Error::InvalidOpcode { opcode: instruction, address: self.pc }),
                 }
             },
             9 => {
                 match n {
-                    0 => {if self.registers[x as usize] != self.registers[y as usize] {self.pc += 2;}},
-                    1 => {self.registers[15] = if self.registers[x as usize] > self.registers[y as usize] {1} else {0}},
-                    2 => {self.registers[15] = if self.registers[x as usize] >= self.registers[y as usize] {1} else {0}},
-                    3 => {self.registers[15] = if self.registers[x as usize] < self.registers[y as usize] {1} else {0}},
-                    4 => {self.registers[15] = if self.registers[x as usize] <= self.registers[y as usize] {1} else {0}},
-                    _ => panic!("Unknown instruction.")
+                    0 => {if self.registers[x as usize] != self.registers[y as usize] {self.skip()?;}},
+                    1 if self.comparisons => {self.registers[15] = if self.registers[x as usize] > self.registers[y as usize] {1} else {0}},
+                    2 if self.comparisons => {self.registers[15] = if self.registers[x as usize] >= self.registers[y as usize] {1} else {0}},
+                    3 if self.comparisons => {self.registers[15] = if self.registers[x as usize] < self.registers[y as usize] {1} else {0}},
+                    4 if self.comparisons => {self.registers[15] = if self.registers[x as usize] <= self.registers[y as usize] {1} else {0}},
+                    _ => return Err(Chip8Error::InvalidOpcode { opcode: instruction, address: self.pc })
                 }
             },
             0xA => {self.i = nnn},
-            0xB => {self.pc = self.registers[0] as u16 + nnn as u16; return;},
+            0xB => {
+                let offset = if self.quirks.jump_uses_vx {self.registers[x as usize]} else {self.registers[0]};
+                self.pc = offset as u16 + nnn;
+                return Ok(());
+            },
             0xC => {
-                let mut rng = rand::thread_rng();
-                let rnum = rng.gen_range(0..256) as u8;
+                let rnum = self.random.next_byte();
                 self.registers[x as usize] = kk as u8 & rnum;
             },
             0xD => {
-                let mut sprite: Vec<u8> = vec![];
+                let (vx, vy) = (self.registers[x as usize], self.registers[y as usize]);
+                let mut addr = self.i as usize;
+                let mut collision = false;
+
+                // Each selected plane gets its own copy of the sprite data,
+                // one after another in memory
+                for plane in [1, 2] {
+                    if self.planes & plane == 0 {
+                        continue;
+                    }
 
-                for i in 0..n {
-                    sprite.push(self.memory[(self.i + i) as usize]);
+                    let mut sprite: Vec<u16> = vec![];
+                    if n == 0 && schip {
+                        // 16x16 sprite, two bytes per row
+                        for _ in 0..16 {
+                            sprite.push(((self.read(addr)? as u16) << 8) | self.read(addr + 1)? as u16);
+                            addr += 2;
+                        }
+                        collision |= self.draw_sprite(&sprite, 16, plane, vx, vy);
+                    } else {
+                        for _ in 0..n {
+                            sprite.push(self.read(addr)? as u16);
+                            addr += 1;
+                        }
+                        collision |= self.draw_sprite(&sprite, 8, plane, vx, vy);
+                    }
                 }
 
-                self.draw_sprite(&sprite, self.registers[x as usize], self.registers[y as usize]);
+                self.registers[15] = if collision {1} else {0};
+                self.present();
             },
             0xE => {
                 match kk {
                     0x9E => {
-                        if program.input_manager.is_key_down(self.key_map[&self.registers[x as usize]]) {self.pc += 2;}
+                        if self.is_key_down(self.registers[x as usize]) {self.skip()?;}
                     },
                     0xA1 => {
-                        if !program.input_manager.is_key_down(self.key_map[&self.registers[x as usize]]) {self.pc += 2;}
+                        if !self.is_key_down(self.registers[x as usize]) {self.skip()?;}
                     },
-                    _ => panic!("Unknown instruction")
+                    _ => return Err(Chip8Error::InvalidOpcode { opcode: instruction, address: self.pc })
                 }
             },
             0xF => {
                 match kk {
+                    // F000 nnnn: load the 16 bit address in the next word into I
+                    0x00 if xo && x == 0 => {
+                        self.i = ((self.fetch(self.pc as usize + 2)? as u16) << 8) | self.fetch(self.pc as usize + 3)? as u16;
+                        self.advance(2)?;
+                    },
+                    0x01 if xo => {self.planes = x as u8 & 0x3},
+                    0x02 if xo && x == 0 => {
+                        let mut pattern = [0; 16];
+                        for (offset, byte) in pattern.iter_mut().enumerate() {
+                            *byte = self.read(self.i as usize + offset)?;
+                        }
+                        self.pattern = Some(pattern);
+                        self.beeper.set_pattern(self.pattern, self.pattern_rate());
+                    },
+                    0x3A if xo => {
+                        self.pitch = self.registers[x as usize];
+                        if self.pattern.is_some() {
+                            self.beeper.set_pattern(self.pattern, self.pattern_rate());
+                        }
+                    },
                     0x07 => {self.registers[x as usize] = self.dt},
                     0x0A => {
-                        match program.input_manager.get_keyboard_events() {
-                            Some(key) => {
-                                let mut key_val: u8 = 0;
-
-                                for entry in &self.key_map {
-                                    if *entry.1 as u32 == key as u32 {
-                                        key_val = *entry.0;
-                                        break;
-                                    }
-                                }
+                        // Only count presses that happen after the wait started
+                        if !self.waiting_for_key {
+                            self.waiting_for_key = true;
+                            self.last_pressed = None;
+                            return Ok(());
+                        }
 
-                                self.registers[x as usize] = key_val;
+                        match self.last_pressed.take() {
+                            Some(key) => {
+                                self.waiting_for_key = false;
+                                self.registers[x as usize] = key;
                             },
                             _ => {
-                                return;
+                                return Ok(());
                             }
                         }
                     },
                     0x15 => {self.dt = self.registers[x as usize]},
-                    0x18 => {self.st = self.registers[x as usize]},
-                    0x1E => {self.i += self.registers[x as usize] as u16},
+                    0x18 => {
+                        self.st = self.registers[x as usize];
+                        self.update_beeper();
+                    },
+                    0x1E => {self.i = self.i.wrapping_add(self.registers[x as usize] as u16)},
                     0x29 => {
-                        self.i = self.sprite_locations[&self.registers[x as usize]];
+                        self.i = self.sprite_locations[&(self.registers[x as usize] & 0xF)];
+                    },
+                    0x30 if schip => {
+                        self.i = BIG_FONT_START + 10 * (self.registers[x as usize] & 0xF) as u16;
                     },
                     0x33 => {
                         let vx = self.registers[x as usize];
@@ -347,28 +948,424 @@ impl Chip8 {
                         let n2 = (vx % 100) / 10;
                         let n3 = vx % 10;
 
-                        self.memory[self.i as usize] = n1;
-                        self.memory[self.i as usize + 1] = n2;
-                        self.memory[self.i as usize + 2] = n3;
+                        self.write(self.i as usize, n1)?;
+                        self.write(self.i as usize + 1, n2)?;
+                        self.write(self.i as usize + 2, n3)?;
                     },
                     0x55 => {
                         for i in 0..=x {
-                            self.memory[(self.i + i) as usize] = self.registers[i as usize];
+                            self.write(self.i as usize + i as usize, self.registers[i as usize])?;
                         }
+                        if self.quirks.load_store_increments_i {self.i = self.i.wrapping_add(x + 1)}
                     },
                     0x65 => {
                         for i in 0..=x {
-                            self.registers[i as usize] = self.memory[(i + self.i) as usize];
+                            self.registers[i as usize] = self.read(self.i as usize + i as usize)?;
+                        }
+                        if self.quirks.load_store_increments_i {self.i = self.i.wrapping_add(x + 1)}
+                    },
+                    0x75 if schip => {
+                        for i in 0..=x as usize {
+                            self.rpl[i] = self.registers[i];
+                        }
+                    },
+                    0x85 if schip => {
+                        for i in 0..=x as usize {
+                            self.registers[i] = self.rpl[i];
                         }
                     },
-                    _ => panic!("Unknown instruction{:#06x}", instruction)
+                    _ => return Err(Chip8Error::InvalidOpcode { opcode: instruction, address: self.pc })
                 }
             }
 
             _ => {}
         }
 
-        self.pc += 2;
+        self.advance(2)
+    }
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Emulator for `platform` with `rom` loaded at 0x200
+    fn machine(platform: Platform, rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::for_platform(platform);
+        chip8.load_rom_data(rom.to_vec()).unwrap();
+        chip8
     }
-}
\ No newline at end of file
+
+    fn run(chip8: &mut Chip8, instructions: usize) {
+        for _ in 0..instructions {
+            chip8.run_single().unwrap();
+        }
+    }
+
+    #[test]
+    fn timers_tick_at_60_hz_at_any_cpu_rate() {
+        for cps in [200, 1000] {
+            // ld v0, 100; ld dt, v0; ld st, v0; loop forever
+            let mut chip8 = machine(Platform::Chip8, &[0x60, 0x64, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06]);
+            chip8.set_cycles_per_second(cps);
+            for _ in 0..60 {
+                chip8.run_frame().unwrap();
+            }
+            assert_eq!(chip8.delay_timer(), 40, "at {} cps", cps);
+            assert_eq!(chip8.sound_timer(), 40, "at {} cps", cps);
+            assert_eq!(chip8.cycle_count(), cps as u64, "at {} cps", cps);
+        }
+    }
+
+    #[test]
+    fn stepping_keeps_the_same_timer_pace() {
+        for cps in [200, 1000] {
+            // ld v0, 100; ld dt, v0; then loop forever
+            let mut chip8 = machine(Platform::Chip8, &[0x60, 0x64, 0xF0, 0x15, 0x12, 0x04]);
+            chip8.set_cycles_per_second(cps);
+            let mut ticks = 0;
+            for _ in 0..cps {
+                ticks += chip8.step().unwrap() as u32;
+            }
+            assert_eq!(ticks, 60, "at {} cps", cps);
+            assert_eq!(chip8.delay_timer(), 40, "at {} cps", cps);
+        }
+
+        // Half a second of steps then half a second of frames is still a second
+        let mut chip8 = machine(Platform::Chip8, &[0x60, 0x64, 0xF0, 0x15, 0x12, 0x04]);
+        chip8.set_cycles_per_second(1000);
+        for _ in 0..500 {
+            chip8.step().unwrap();
+        }
+        for _ in 0..30 {
+            chip8.run_frame().unwrap();
+        }
+        assert_eq!(chip8.cycle_count(), 1000);
+        assert_eq!(chip8.delay_timer(), 40);
+    }
+
+    #[test]
+    fn invalid_opcode_reports_where_it_was() {
+        // ld v0, 0; 5xy1 doesn't exist
+        let mut chip8 = machine(Platform::Chip8, &[0x60, 0x00, 0x50, 0x01]);
+        run(&mut chip8, 1);
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::InvalidOpcode { opcode: 0x5001, address: 0x202 })));
+    }
+
+    #[test]
+    fn seventeenth_call_overflows_the_stack() {
+        // call 0x200, forever
+        let mut chip8 = machine(Platform::Chip8, &[0x22, 0x00]);
+        run(&mut chip8, 16);
+        assert_eq!(chip8.sp(), 16);
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::StackOverflow { address: 0x200 })));
+    }
+
+    #[test]
+    fn return_with_empty_stack_underflows() {
+        let mut chip8 = machine(Platform::Chip8, &[0x00, 0xEE]);
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::StackUnderflow { address: 0x200 })));
+    }
+
+    #[test]
+    fn store_past_the_end_of_memory() {
+        // ld i, 0xfff; ld [i], v1 writes 0xfff and 0x1000
+        let mut chip8 = machine(Platform::Chip8, &[0xAF, 0xFF, 0xF1, 0x55]);
+        run(&mut chip8, 1);
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::MemoryOutOfBounds { access: 0x1000, address: 0x202 })));
+    }
+
+    #[test]
+    fn running_off_the_end_of_memory() {
+        // jp 0xffe, where 0nnn is ignored and the next fetch is past the end
+        let mut chip8 = machine(Platform::Chip8, &[0x1F, 0xFE]);
+        run(&mut chip8, 2);
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::MemoryOutOfBounds { access: 0x1000, address: 0x1000 })));
+    }
+
+    #[test]
+    fn loading_bad_roms() {
+        let mut chip8 = Chip8::for_platform(Platform::Chip8);
+        assert!(chip8.load_rom_data(vec![0; 0xE00]).is_ok());
+        assert!(matches!(chip8.load_rom_data(vec![0; 0xE01]), Err(Chip8Error::RomTooLarge { size: 0xE01, max: 0xE00 })));
+        assert!(matches!(chip8.load_rom_from_file("no/such/rom.ch8"), Err(Chip8Error::Io(_))));
+    }
+
+    // Emulator with `rom` loaded, using modern quirks changed by `quirk`
+    fn quirky(quirk: impl Fn(&mut Quirks), rom: &[u8]) -> Chip8 {
+        let mut quirks = Quirks::modern();
+        quirk(&mut quirks);
+        let mut chip8 = Chip8::with_quirks(quirks);
+        chip8.load_rom_data(rom.to_vec()).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn shift_quirk() {
+        // ld v0, 8; ld v1, 5; shr v0, v1
+        let rom = [0x60, 0x08, 0x61, 0x05, 0x80, 0x16];
+        for (uses_vy, v0, vf) in [(false, 4, 0), (true, 2, 1)] {
+            let mut chip8 = quirky(|quirks| quirks.shift_uses_vy = uses_vy, &rom);
+            run(&mut chip8, 3);
+            assert_eq!((chip8.registers()[0], chip8.registers()[15]), (v0, vf), "shift_uses_vy {}", uses_vy);
+        }
+    }
+
+    #[test]
+    fn load_store_quirk() {
+        // ld i, 0x300; ld [i], v2; ld v2, [i]
+        let rom = [0xA3, 0x00, 0xF2, 0x55, 0xF2, 0x65];
+        for (increments, after_store, after_load) in [(false, 0x300, 0x300), (true, 0x303, 0x306)] {
+            let mut chip8 = quirky(|quirks| quirks.load_store_increments_i = increments, &rom);
+            run(&mut chip8, 2);
+            assert_eq!(chip8.i(), after_store, "load_store_increments_i {}", increments);
+            run(&mut chip8, 1);
+            assert_eq!(chip8.i(), after_load, "load_store_increments_i {}", increments);
+        }
+    }
+
+    #[test]
+    fn jump_quirk() {
+        // ld v0, 4; ld v2, 0x10; jp v0, 0x220
+        let rom = [0x60, 0x04, 0x62, 0x10, 0xB2, 0x20];
+        for (uses_vx, pc) in [(false, 0x224), (true, 0x230)] {
+            let mut chip8 = quirky(|quirks| quirks.jump_uses_vx = uses_vx, &rom);
+            run(&mut chip8, 3);
+            assert_eq!(chip8.pc(), pc, "jump_uses_vx {}", uses_vx);
+        }
+    }
+
+    #[test]
+    fn logic_quirk() {
+        for op in [0x1, 0x2, 0x3] {
+            // ld vf, 5; or/and/xor v0, v1
+            let rom = [0x6F, 0x05, 0x80, 0x10 | op];
+            for (resets, vf) in [(false, 5), (true, 0)] {
+                let mut chip8 = quirky(|quirks| quirks.logic_resets_vf = resets, &rom);
+                run(&mut chip8, 2);
+                assert_eq!(chip8.registers()[15], vf, "8xy{} with logic_resets_vf {}", op, resets);
+            }
+        }
+    }
+
+    #[test]
+    fn clip_quirk() {
+        // ld v0, 60; ld v1, 31; ld i, .row; drw v0, v1, 2; .row db 0xff, 0xff
+        let rom = [0x60, 0x3C, 0x61, 0x1F, 0xA2, 0x08, 0xD0, 0x12, 0xFF, 0xFF];
+        for clip in [false, true] {
+            let mut chip8 = quirky(|quirks| quirks.clip_sprites = clip, &rom);
+            run(&mut chip8, 4);
+            assert!(chip8.pixel(63, 31));
+            // The rest of the row wraps to the left edge, the second row to the top
+            assert_eq!(chip8.pixel(0, 31), !clip, "clip_sprites {}", clip);
+            assert_eq!(chip8.pixel(3, 31), !clip, "clip_sprites {}", clip);
+            assert!(!chip8.pixel(4, 31));
+            assert_eq!(chip8.pixel(60, 0), !clip, "clip_sprites {}", clip);
+        }
+    }
+
+    #[test]
+    fn hires_switch() {
+        // high; drw v0, v0, 1 (font 0); low
+        let mut chip8 = machine(Platform::SuperChip, &[0x00, 0xFF, 0xD0, 0x01, 0x00, 0xFE]);
+        run(&mut chip8, 2);
+        assert_eq!((chip8.width(), chip8.height()), (HIRES_WIDTH, HIRES_HEIGHT));
+        assert!(chip8.pixel(0, 0));
+        run(&mut chip8, 1);
+        assert_eq!((chip8.width(), chip8.height()), (WIDTH, HEIGHT));
+        assert!(chip8.framebuffer().iter().all(|pixel| *pixel == 0));
+
+        // Plain CHIP-8 ignores it as a 0nnn call
+        let mut chip8 = machine(Platform::Chip8, &[0x00, 0xFF]);
+        run(&mut chip8, 1);
+        assert_eq!(chip8.width(), WIDTH);
+    }
+
+    #[test]
+    fn scrolling() {
+        // ld v0, 10; ld i, .dot; drw v0, v0, 1; scd 3; scr; scl; .dot db 0x80
+        let mut chip8 = machine(Platform::SuperChip, &[0x60, 0x0A, 0xA2, 0x0C, 0xD0, 0x01, 0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x80]);
+        run(&mut chip8, 3);
+        assert!(chip8.pixel(10, 10));
+        run(&mut chip8, 1);
+        assert!(chip8.pixel(10, 13) && !chip8.pixel(10, 10));
+        run(&mut chip8, 1);
+        assert!(chip8.pixel(14, 13) && !chip8.pixel(10, 13));
+        run(&mut chip8, 1);
+        assert!(chip8.pixel(10, 13) && !chip8.pixel(14, 13));
+        assert_eq!(chip8.framebuffer().iter().filter(|pixel| **pixel != 0).count(), 1);
+    }
+
+    #[test]
+    fn big_sprites() {
+        // high; ld i, .sprite; drw v0, v0, 0; drw v0, v0, 0, with every row
+        // of .sprite 0x8001
+        let mut rom = vec![0x00, 0xFF, 0xA2, 0x08, 0xD0, 0x00, 0xD0, 0x00];
+        for _ in 0..16 {
+            rom.extend_from_slice(&[0x80, 0x01]);
+        }
+        let mut chip8 = machine(Platform::SuperChip, &rom);
+        run(&mut chip8, 3);
+        assert!(chip8.pixel(0, 0) && chip8.pixel(15, 0) && chip8.pixel(0, 15) && chip8.pixel(15, 15));
+        assert!(!chip8.pixel(1, 0) && !chip8.pixel(16, 0) && !chip8.pixel(0, 16));
+        assert_eq!(chip8.registers()[15], 0);
+
+        run(&mut chip8, 1);
+        assert_eq!(chip8.registers()[15], 1);
+        assert!(chip8.framebuffer().iter().all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn big_font() {
+        // ld v3, 7; ld hf, v3
+        let mut chip8 = machine(Platform::SuperChip, &[0x63, 0x07, 0xF3, 0x30]);
+        run(&mut chip8, 2);
+        assert_eq!(chip8.i(), BIG_FONT_START + 70);
+        let seven: Vec<u8> = (0..10).map(|row| chip8.peek(chip8.i() as usize + row).unwrap()).collect();
+        assert_eq!(seven, [0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18]);
+    }
+
+    #[test]
+    fn rpl_flags() {
+        // ld v0, 1; ld v1, 2; ld v2, 3; ld r, v2; ld v0, 9; ld v1, 9;
+        // ld v2, 9; ld v1, r
+        let mut chip8 = machine(Platform::SuperChip, &[
+            0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xF2, 0x75,
+            0x60, 0x09, 0x61, 0x09, 0x62, 0x09, 0xF1, 0x85,
+        ]);
+        run(&mut chip8, 8);
+        assert_eq!(chip8.registers()[..3], [1, 2, 9]);
+    }
+
+    #[test]
+    fn exit_halts() {
+        let mut chip8 = machine(Platform::SuperChip, &[0x00, 0xFD]);
+        run(&mut chip8, 3);
+        assert!(chip8.is_halted());
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn long_load() {
+        let mut chip8 = machine(Platform::XoChip, &[0xF0, 0x00, 0x12, 0x34]);
+        run(&mut chip8, 1);
+        assert_eq!((chip8.i(), chip8.pc()), (0x1234, 0x204));
+    }
+
+    #[test]
+    fn skips_jump_over_long_loads() {
+        // ld v0, 0; se v0, 0; i := long 0x1234
+        let rom = [0x60, 0x00, 0x30, 0x00, 0xF0, 0x00, 0x12, 0x34];
+        let mut chip8 = machine(Platform::XoChip, &rom);
+        run(&mut chip8, 2);
+        assert_eq!(chip8.pc(), 0x208);
+
+        // Only XO-CHIP has the 4 byte instruction
+        let mut chip8 = machine(Platform::SuperChip, &rom);
+        run(&mut chip8, 2);
+        assert_eq!(chip8.pc(), 0x206);
+    }
+
+    #[test]
+    fn register_ranges() {
+        // ld v1, 1; ld v2, 2; ld v3, 3; ld i, 0x300; save v1 - v3;
+        // ld i, 0x310; save v3 - v1; ld i, 0x300; load v3 - v1
+        let mut chip8 = machine(Platform::XoChip, &[
+            0x61, 0x01, 0x62, 0x02, 0x63, 0x03, 0xA3, 0x00, 0x51, 0x32,
+            0xA3, 0x10, 0x53, 0x12, 0xA3, 0x00, 0x53, 0x13,
+        ]);
+        run(&mut chip8, 5);
+        let saved: Vec<u8> = (0x300..0x303).map(|addr| chip8.peek(addr).unwrap()).collect();
+        assert_eq!(saved, [1, 2, 3]);
+        assert_eq!(chip8.i(), 0x300);
+
+        run(&mut chip8, 2);
+        let saved: Vec<u8> = (0x310..0x313).map(|addr| chip8.peek(addr).unwrap()).collect();
+        assert_eq!(saved, [3, 2, 1]);
+
+        run(&mut chip8, 2);
+        assert_eq!(chip8.registers()[1..4], [3, 2, 1]);
+        assert_eq!(chip8.i(), 0x300);
+    }
+
+    #[test]
+    fn plane_masks() {
+        // plane 2; ld i, .data; drw v0, v0, 1; plane 3; ld v0, 8;
+        // drw v0, v0, 1; plane 1; cls; .data db 0x80, 0x80
+        let mut chip8 = machine(Platform::XoChip, &[
+            0xF2, 0x01, 0xA2, 0x10, 0xD0, 0x01, 0xF3, 0x01,
+            0x60, 0x08, 0xD0, 0x01, 0xF1, 0x01, 0x00, 0xE0,
+            0x80, 0x80,
+        ]);
+        run(&mut chip8, 3);
+        assert_eq!(chip8.framebuffer()[0], 2);
+
+        // Both planes take their own row of sprite data
+        run(&mut chip8, 3);
+        assert_eq!(chip8.framebuffer()[8 * WIDTH + 8], 3);
+
+        // Clearing only touches the selected plane
+        run(&mut chip8, 2);
+        assert_eq!(chip8.framebuffer()[0], 2);
+        assert_eq!(chip8.framebuffer()[8 * WIDTH + 8], 2);
+    }
+
+    #[test]
+    fn scroll_up() {
+        // ld v0, 10; ld i, .dot; drw v0, v0, 1; scu 3; .dot db 0x80
+        let mut chip8 = machine(Platform::XoChip, &[0x60, 0x0A, 0xA2, 0x08, 0xD0, 0x01, 0x00, 0xD3, 0x80]);
+        run(&mut chip8, 4);
+        assert!(chip8.pixel(10, 7) && !chip8.pixel(10, 10));
+    }
+
+    #[test]
+    fn end_of_memory() {
+        // PC can't step past 0xFFFF
+        let mut chip8 = machine(Platform::XoChip, &[]);
+        chip8.pc = 0xFFFE;
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::MemoryOutOfBounds { access: 0x10000, address: 0xFFFE })));
+
+        // Nor skip past it
+        chip8.memory[0xFFFE] = 0x30;
+        assert!(matches!(chip8.run_single(), Err(Chip8Error::MemoryOutOfBounds { access: 0x10000, address: 0xFFFE })));
+
+        // I wraps after a store or load at the very end
+        let mut chip8 = machine(Platform::XoChip, &[0xF0, 0x55, 0xF0, 0x65]);
+        chip8.i = 0xFFFF;
+        run(&mut chip8, 1);
+        assert_eq!(chip8.i(), 0);
+        chip8.i = 0xFFFF;
+        run(&mut chip8, 1);
+        assert_eq!(chip8.i(), 0);
+    }
+
+    #[test]
+    fn comparisons_are_opt_in() {
+        // ld v0, 5; ld v1, 7; then 9xy1 - 9xy4 on v0, v1
+        for (op, vf) in [(0x1, 0), (0x2, 0), (0x3, 1), (0x4, 1)] {
+            let rom = [0x60, 0x05, 0x61, 0x07, 0x90, 0x10 | op];
+            let mut chip8 = machine(Platform::Chip8, &rom);
+            run(&mut chip8, 2);
+            assert!(matches!(chip8.run_single(), Err(Chip8Error::InvalidOpcode { address: 0x204, .. })), "9xy{}", op);
+
+            let mut chip8 = machine(Platform::Chip8, &rom);
+            chip8.set_comparisons(true);
+            run(&mut chip8, 3);
+            assert_eq!(chip8.registers()[15], vf, "9xy{}", op);
+        }
+
+        // Equal operands, where >= and <= differ from > and <
+        for (op, vf) in [(0x1, 0), (0x2, 1), (0x3, 0), (0x4, 1)] {
+            let mut chip8 = machine(Platform::Chip8, &[0x60, 0x05, 0x61, 0x05, 0x90, 0x10 | op]);
+            chip8.set_comparisons(true);
+            run(&mut chip8, 3);
+            assert_eq!(chip8.registers()[15], vf, "9xy{} on equal values", op);
+        }
+    }
+}
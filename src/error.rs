@@ -0,0 +1,39 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Chip8Error {
+    // Opcode the interpreter doesn't know, and where it was fetched from
+    InvalidOpcode { opcode: u16, address: u16 },
+    // 2nnn with all 16 stack slots in use
+    StackOverflow { address: u16 },
+    // 00EE with nothing on the stack
+    StackUnderflow { address: u16 },
+    // Read or write past the end of memory by the instruction at `address`
+    MemoryOutOfBounds { access: usize, address: u16 },
+    RomTooLarge { size: usize, max: usize },
+    // Save state that can't be loaded, and why
+    BadSaveState(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode { opcode, address } => write!(f, "Invalid opcode {:#06x} at {:#05x}", opcode, address),
+            Chip8Error::StackOverflow { address } => write!(f, "Stack overflow at {:#05x}", address),
+            Chip8Error::StackUnderflow { address } => write!(f, "Stack underflow at {:#05x}", address),
+            Chip8Error::MemoryOutOfBounds { access, address } => write!(f, "Memory access to {:#06x} out of bounds at {:#05x}", access, address),
+            Chip8Error::RomTooLarge { size, max } => write!(f, "Rom is {} bytes, the maximum is {}", size, max),
+            Chip8Error::BadSaveState(reason) => write!(f, "Bad save state: {}", reason),
+            Chip8Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+impl From<io::Error> for Chip8Error {
+    fn from(err: io::Error) -> Chip8Error {
+        Chip8Error::Io(err)
+    }
+}
@@ -0,0 +1,17 @@
+// The emulator core and tools. main.rs is the command line frontend, and
+// anything public here is there for other frontends and tests to use too.
+
+pub mod emulator;
+pub mod backend;
+pub mod error;
+pub mod audio;
+pub mod quirks;
+pub mod state;
+pub mod random;
+pub mod rewind;
+pub mod debugger;
+pub mod trace;
+pub mod assembler;
+pub mod disassembler;
+#[cfg(feature = "window")]
+pub mod window;
@@ -1,213 +1,324 @@
 
+#[cfg(feature = "window")]
 use rgraphics::*;
 
-mod emulator;
-mod assembler;
-
-fn main() {
+use std::{fs, io, str::FromStr};
+
+use chip8::{emulator, quirks, debugger, trace, assembler, disassembler};
+#[cfg(feature = "window")]
+use chip8::window;
+
+const USAGE: &str = "\
+Usage: chip8 <command> [options]
+
+Commands:
+  run <rom>                 Run a rom
+  debug <rom>               Run a rom under the terminal debugger
+  trace <rom>               Print a trace of the first instructions a rom runs
+  asm <src> -o <rom>        Assemble a source file
+  disasm <rom> [-o <src>]   Disassemble a rom, to stdout unless -o is given
+
+Emulator options (run, debug, trace):
+  --platform <chip8 | schip | xochip>
+  --quirks <vip | chip48 | schip | xochip | modern>
+  --cps <n>                 Instructions per second, 800 by default
+  --seed <n>                Seed for Cxkk, so runs repeat exactly
+  --trace <file>            Log every instruction to a file
+  --trace-format <default | compact | template>
+  --comparisons             Enable the non-standard 9xy1 - 9xy4 opcodes
+
+run:
+  --scale <n>               Fraction of the window the screen fills, above 0 and
+                            up to 1
+  --frames <n>              Headless builds: frames to run, 60 by default
+  --wav <file>              Headless builds: write the sound to a wav file
+
+trace:
+  --cycles <n>              Instructions to trace, 1000 by default
+  -o <file>                 Write the trace to a file instead of stdout
+
+asm, disasm:
+  --comparisons             Allow gt, gte, lt and lte
+  --map                     asm: print where each part of the program went";
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+// Remove `flag <value>` from the arguments and return the value, leaving
+// the rest in order.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == flag)?;
+    args.remove(pos);
+    if pos >= args.len() {
+        exit_with(&format!("Expected a value after {}.", flag));
+    }
+    Some(args.remove(pos))
+}
+
+// Remove `flag` and return whether it was there
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|arg| arg == flag) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        },
+        None => false,
+    }
+}
+
+fn take_number<T: FromStr>(args: &mut Vec<String>, flag: &str) -> Option<T> {
+    let value = take_flag(args, flag)?;
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => exit_with(&format!("{} must be a number, got {}.", flag, value)),
+    }
+}
+
+// `--scale` is a fraction of the window, so anything outside 0 - 1 is a
+// mistake rather than something to quietly clamp
+fn take_scale(args: &mut Vec<String>) -> Option<f32> {
+    let scale: f32 = take_number(args, "--scale")?;
+    if !(scale > 0.0 && scale <= 1.0) {
+        exit_with(&format!("--scale is the fraction of the window the screen fills, above 0 and up to 1, got {}.", scale));
+    }
+    Some(scale)
+}
+
+// The one positional argument left once every option has been taken out
+fn take_path(args: Vec<String>, what: &str) -> String {
+    if let Some(unknown) = args.iter().find(|arg| arg.starts_with('-')) {
+        exit_with(&format!("Unknown option {}.\n\n{}", unknown, USAGE));
+    }
+    match args.len() {
+        0 => exit_with(&format!("Expected a {} path.\n\n{}", what, USAGE)),
+        1 => args.into_iter().next().unwrap(),
+        _ => exit_with(&format!("Expected one {} path, got {}.", what, args.join(" "))),
+    }
+}
+
+// Build an emulator from `--platform <chip8 | schip | xochip>`,
+// `--quirks <vip | chip48 | schip | xochip | modern>`, `--comparisons`, `--cps <n>`,
+// `--seed <n>` and `--trace <file>` with an optional
+// `--trace-format <default | compact | template>`. Quirks default to
+// whatever the platform's ROMs usually expect.
+fn chip8_from_args(args: &mut Vec<String>) -> emulator::Chip8 {
+    let platform = match take_flag(args, "--platform") {
+        None => quirks::Platform::Chip8,
+        Some(name) => quirks::Platform::from_name(&name).unwrap_or_else(|| {
+            exit_with(&format!("Unknown platform {}. Expected chip8, schip or xochip.", name))
+        }),
+    };
+
+    let mut chip8 = emulator::Chip8::for_platform(platform);
+
+    if let Some(name) = take_flag(args, "--quirks") {
+        match quirks::Quirks::from_name(&name) {
+            Some(quirks) => chip8.set_quirks(quirks),
+            None => exit_with(&format!("Unknown quirks preset {}. Expected vip, chip48, schip, xochip or modern.", name)),
+        }
+    }
+
+    if take_switch(args, "--comparisons") {
+        chip8.set_comparisons(true);
+    }
+
+    chip8.set_cycles_per_second(take_number(args, "--cps").unwrap_or(800));
+
+    if let Some(seed) = take_number(args, "--seed") {
+        chip8.set_seed(seed);
+    }
+
+    let format = trace_format(take_flag(args, "--trace-format"));
+    if let Some(path) = take_flag(args, "--trace") {
+        match trace::Tracer::to_file(&path, format) {
+            Ok(tracer) => chip8.set_tracer(Some(tracer)),
+            Err(err) => exit_with(&format!("Unable to open trace file {}: {}", path, err)),
+        }
+    }
+
+    chip8
+}
+
+// Anything that isn't a preset name is taken as a template
+fn trace_format(name: Option<String>) -> trace::TraceFormat {
+    match name {
+        None => trace::TraceFormat::Default,
+        Some(name) => trace::TraceFormat::from_name(&name).unwrap_or(trace::TraceFormat::Template(name)),
+    }
+}
+
+fn load_rom(chip8: &mut emulator::Chip8, path: &str) {
+    if let Err(err) = chip8.load_rom_from_file(path) {
+        exit_with(&format!("Unable to load {}: {}", path, err));
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| exit_with(&format!("Unable to read {}: {}", path, err)))
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    if let Err(err) = fs::write(path, data) {
+        exit_with(&format!("Unable to write {}: {}", path, err));
+    }
+}
+
+fn assembler_options(args: &mut Vec<String>) -> assembler::Options {
+    assembler::Options { comparisons: take_switch(args, "--comparisons") }
+}
+
+fn debug(mut args: Vec<String>) {
+    let mut chip8 = chip8_from_args(&mut args);
+    let path = take_path(args, "rom");
+    load_rom(&mut chip8, &path);
+    debugger::repl(&mut chip8);
+}
+
+// Run `--cycles` instructions with every one logged, stopping early if the
+// rom crashes or exits. Timers tick at 60 Hz of emulated time, as they would
+// in a normal run. Takes --trace-format before chip8_from_args does.
+fn trace(mut args: Vec<String>) {
+    let format = trace_format(take_flag(&mut args, "--trace-format"));
+    let mut chip8 = chip8_from_args(&mut args);
+    let cycles: u64 = take_number(&mut args, "--cycles").unwrap_or(1000);
+    let tracer = match take_flag(&mut args, "-o") {
+        Some(out) => trace::Tracer::to_file(&out, format)
+            .unwrap_or_else(|err| exit_with(&format!("Unable to open {}: {}", out, err))),
+        None => trace::Tracer::new(Box::new(io::stdout()), format),
+    };
+    let path = take_path(args, "rom");
+    load_rom(&mut chip8, &path);
+    chip8.set_tracer(Some(tracer));
+
+    for _ in 0..cycles {
+        if chip8.is_halted() {
+            break;
+        }
+        if let Err(err) = chip8.step() {
+            eprintln!("Emulation stopped: {}", err);
+            break;
+        }
+    }
+}
+
+fn asm(mut args: Vec<String>) {
+    let options = assembler_options(&mut args);
+    let out = take_flag(&mut args, "-o").unwrap_or_else(|| exit_with("asm needs an output path, -o <rom>."));
+    let show_map = take_switch(&mut args, "--map");
+    let path = take_path(args, "source");
+
+    let source = String::from_utf8(read_file(&path))
+        .unwrap_or_else(|_| exit_with(&format!("{} is not valid UTF-8.", path)));
+    let assembled = if show_map {
+        assembler::assemble_with_map(&source, &path, &options).map(|(rom, map)| (rom, Some(map)))
+    } else {
+        assembler::assemble(&source, &path, &options).map(|rom| (rom, None))
+    };
+    match assembled {
+        Ok((rom, map)) => {
+            write_file(&out, &rom);
+            if let Some(map) = map {
+                println!("{}", map);
+            }
+        },
+        Err(errors) => {
+            for err in &errors {
+                eprintln!("{}\n", err);
+            }
+            exit_with(&format!("{} error(s), nothing written.", errors.len()));
+        }
+    }
+}
+
+fn disasm(mut args: Vec<String>) {
+    let options = assembler_options(&mut args);
+    let out = take_flag(&mut args, "-o");
+    let path = take_path(args, "rom");
+
+    let source = disassembler::disassemble(&read_file(&path), &options);
+    match out {
+        Some(out) => write_file(&out, source.as_bytes()),
+        None => print!("{}", source),
+    }
+}
+
+// Headless build: run the rom for a fixed number of 60 Hz frames and print
+// the final screen. Sound is written to the optional wav path.
+#[cfg(not(feature = "window"))]
+fn run(mut args: Vec<String>) {
+    let mut chip8 = chip8_from_args(&mut args);
+    let frames: u32 = take_number(&mut args, "--frames").unwrap_or(60);
+    let wav_path = take_flag(&mut args, "--wav");
+    // Only means something with a window
+    take_scale(&mut args);
+    let path = take_path(args, "rom");
+    load_rom(&mut chip8, &path);
+
+    let recorder = chip8::audio::WavRecorder::new(chip8::audio::AudioSettings::default());
+    chip8.set_beeper(Box::new(recorder.clone()));
+
+    for _ in 0..frames {
+        if let Err(err) = chip8.run_frame() {
+            eprintln!("Emulation stopped: {}", err);
+            break;
+        }
+    }
+
+    if let Some(wav_path) = wav_path {
+        if let Err(err) = recorder.save(&wav_path) {
+            exit_with(&format!("Unable to write {}: {}", wav_path, err));
+        }
+    }
+
+    for y in 0..chip8.height() {
+        let row: String = (0..chip8.width()).map(|x| if chip8.pixel(x, y) {'#'} else {'.'}).collect();
+        println!("{}", row);
+    }
+}
+
+#[cfg(feature = "window")]
+fn run(mut args: Vec<String>) {
+    let mut chip8 = chip8_from_args(&mut args);
+    let scale = take_scale(&mut args);
+    let path = take_path(args, "rom");
+    load_rom(&mut chip8, &path);
 
     let (program, mut event_loop) = Program::new();
 
-    let mut chip8 = emulator::Chip8::new(&program);
-
-    let asm = r#"
-
-        jp .start
-        
-        .setup
-            ld v0, 128      ; Ball sprite 2000
-
-            ld v1, 128      ; paddle 2001
-            ld v2, 128
-            ld v3, 128
-            ld v4, 128
-            ld v5, 128
-
-            ldi, 2000       ; Sprites stored at 2000
-            dumpreg v5      ; Save sprites to memory
-            ret
-
-        .start
-            call .setup
-
-            ld v0, 16       ; Left paddle y
-            ld v1, 16       ; Right paddle y
-
-            ld v2, 0        ; Ball x
-            ld v3, 0        ; Ball y
-            ld v4, 0        ; Ball x direction (0 is right, 1 is left)
-            ld v7, 0        ; Ball y direction (0 is up, 1 is down)
-
-            ld v5, 0        ; Input
-            ld v6, 0        ; Ball collision -- 0 is false, 1 is true
-
-            ld v8, 0        ; left score
-            ld v9, 0        ; right score
-
-                            ;v10-v14 are general purpose
-            
-            call .reset_ball_left
-
-            .game_loop
-                call .main_loop
-                jp .game_loop
-
-            .end
-            jp .end
-
-        .main_loop
-            call .update_paddles
-            call .update_ball
-            call .check_for_win
-            call .draw_sprites
-            ret
-
-        .draw_score
-            ldsprt v8
-            ld v10, 16
-            ld v11, 0
-            drw v10, v11, 5
-
-            ldsprt v9
-            ld v10, 42
-            drw v10, v11, 5
-            ret
-
-
-        .check_for_win
-            sne v2, 0
-            call .right_side_point
-            
-            sne v2, 63
-            call .left_side_point
-            ret
-
-            .right_side_point
-            call .reset_ball_left
-            add v9, 1
-            ret
-
-            .left_side_point
-            call .reset_ball_right
-            add v8, 1
-            ret
-
-        .draw_sprites
-            cls
-            ld v15, 0
-
-            ldi 2001
-            ld v10, 62
-            drw v10, v1, 5
-            ld v10, 1
-            drw v10, v0, 5
-            ldi 2000
-            drw v2, v3, 1
-            ld v6, v15
-
-            call .draw_score
-
-            ret
-
-        .update_paddles
-            ld v10, 1
-            sknp v10
-            add v0, 1
-
-            ld v10, 2
-            ld v11, 1
-            sknp v10
-            sub v0, v11
-
-            ld v10, 9
-            sknp v10
-            add v1, 1
-
-            ld v10, 0
-            ld v11, 1
-            sknp v10
-            sub v1, v11
-
-            ret
+    let mut window = window::Window::new(&program);
+    window.attach(&mut chip8);
+    window.set_save_path(&path);
+    if let Some(scale) = scale {
+        window.set_scale(scale);
+    }
 
-        .update_ball
-
-            ld v14, 1           ; ball speed
-
-            sne v6, 1
-            call .switch_ball_x_direction
-
-            sne v4, 0
-            add v2, v14
-
-            sne v4, 1
-            sub v2, v14
-
-            ld v10, 0
-            sne v3, v10
-            call .switch_ball_y_direction
-
-            ld v10, 31
-            gte v3, v10
-            sne v15, 1
-            call .switch_ball_y_direction
-
-
-            sne v7, 0
-            add v3, v14
-
-            sne v7, 1
-            sub v3, v14
-
-            ret
-
-
-        .reset_ball_left
-
-            rnd v10, 31
-            ld v2, 8
-            ld v3, v10
-            ld v4, 0
-            ret
-
-
-        .reset_ball_right
-            rnd v10, 31
-            ld v2, 47
-            ld v3, v10
-            ld v4, 1
-            ret
-
-        .switch_ball_x_direction
-            sne v4, 0
-            jp .switch_ball_0_to_1
-
-            ld v4, 0
-            ret 
-
-            .switch_ball_0_to_1
-                ld v4, 1
-                ret
-
-        .switch_ball_y_direction
-            sne v7, 0
-            jp .switch_ball_y_0_to_1
-
-            ld v7, 0
-            ret
-
-            .switch_ball_y_0_to_1
-                ld v7, 1
-                ret
-                   
-
-    "#;
-
-    //chip8.load_rom_data(assembler::assemble(&asm));
-    chip8.load_rom_from_file("./roms/tetris.ch8");
-    chip8.set_cycles_per_second(800);
+    #[cfg(feature = "audio")]
+    match chip8::audio::DeviceBeeper::new(chip8::audio::AudioSettings::default()) {
+        Some(beeper) => chip8.set_beeper(Box::new(beeper)),
+        None => eprintln!("No audio device, running without sound."),
+    }
 
     rgraphics::run(program, &mut event_loop, &mut |program| {
-        chip8.clock(program);
+        window.update(&mut chip8, program);
     });
-}
\ No newline at end of file
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        exit_with(USAGE);
+    }
+
+    let command = args.remove(0);
+    match command.as_str() {
+        "run" => run(args),
+        "debug" => debug(args),
+        "trace" => trace(args),
+        "asm" => asm(args),
+        "disasm" => disasm(args),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => exit_with(&format!("Unknown command {}.\n\n{}", command, USAGE)),
+    }
+}
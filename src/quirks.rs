@@ -0,0 +1,124 @@
+// Instruction set the interpreter accepts. Opcodes from a later platform
+// are invalid on an earlier one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    Chip8,
+    // SUPER-CHIP 1.1: hires mode, scrolling, big sprites and font, RPL flags
+    SuperChip,
+    // XO-CHIP: everything in SUPER-CHIP plus 64K memory, two bitplanes and
+    // audio patterns
+    XoChip,
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "schip" | "superchip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    // Quirks ROMs for this platform usually expect
+    pub fn default_quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::modern(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xochip(),
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000,
+        }
+    }
+}
+
+// Behaviors that differ between CHIP-8 interpreters. ROMs are usually
+// written against one of them, so pick the preset matching where the ROM
+// came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // Fx55/Fx65 leave I pointing past the last register saved or loaded
+    pub load_store_increments_i: bool,
+    // Bnnn jumps to nnn + Vx (x being the high nibble of nnn) instead of nnn + V0
+    pub jump_uses_vx: bool,
+    // 8xy1/8xy2/8xy3 set VF to 0
+    pub logic_resets_vf: bool,
+    // Dxyn cuts sprites off at the screen edge instead of wrapping them
+    pub clip_sprites: bool,
+}
+
+impl Quirks {
+    // The original interpreter on the RCA COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    // SUPER-CHIP 1.1, same as CHIP-48 for these opcodes
+    pub fn superchip() -> Quirks {
+        Quirks::chip48()
+    }
+
+    // What most modern interpreters (and this one, by default) do
+    pub fn modern() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    // Octo, which XO-CHIP ROMs are written in: VIP shifts and loads, but
+    // sprites wrap
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" | "cosmac" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::superchip()),
+            "xochip" | "octo" => Some(Quirks::xochip()),
+            "modern" => Some(Quirks::modern()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::modern()
+    }
+}
@@ -0,0 +1,167 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+// Random numbers for Cxkk. The sequence only depends on the seed, so a run
+// with the same seed and input always plays out the same way. A recorded
+// stream can be replayed in place of the generator.
+pub struct Random {
+    seed: u64,
+    // What StdRng uses, but its position in the stream can be saved and
+    // restored directly
+    rng: ChaCha12Rng,
+
+    // Bytes handed out since the last reseed, to find the place in a replay
+    draws: u64,
+
+    // Handed out in order before the generator takes over
+    replay: Vec<u8>,
+
+    // Every byte handed out while recording is on
+    recording: Option<Vec<u8>>,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random {
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            draws: 0,
+            replay: vec![],
+            recording: None,
+        }
+    }
+
+    // Seeded from the system so normal runs differ
+    pub fn from_entropy() -> Random {
+        Random::new(rand::thread_rng().gen())
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+
+    // Words of the ChaCha stream used so far
+    pub fn position(&self) -> u64 {
+        self.rng.get_word_pos() as u64
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+        self.draws = 0;
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        let byte = match self.replay.get(self.draws as usize) {
+            Some(byte) => *byte,
+            None => self.rng.gen(),
+        };
+        self.draws += 1;
+
+        if let Some(recording) = self.recording.as_mut() {
+            recording.push(byte);
+        }
+        byte
+    }
+
+    // Jump to the place a snapshot stored, in constant time however far
+    // into the stream it is
+    pub fn restore(&mut self, seed: u64, draws: u64, position: u64) {
+        if seed != self.seed {
+            self.seed = seed;
+            self.rng = ChaCha12Rng::seed_from_u64(seed);
+        }
+        self.rng.set_word_pos(position as u128);
+        self.draws = draws;
+    }
+
+    // Start recording from the next byte, dropping any earlier recording
+    pub fn start_recording(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    // Stop recording and return what was recorded
+    pub fn stop_recording(&mut self) -> Vec<u8> {
+        self.recording.take().unwrap_or_default()
+    }
+
+    // Hand out `stream` from the start, then continue from the seed
+    pub fn replay(&mut self, stream: Vec<u8>) {
+        self.replay = stream;
+        let seed = self.seed;
+        self.set_seed(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Chip8;
+
+    // Emulator looping on rnd v0, 0xff
+    fn machine(seed: u64) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.set_seed(seed);
+        chip8.load_rom_data(vec![0xC0, 0xFF, 0x12, 0x00]).unwrap();
+        chip8
+    }
+
+    // The next `count` values Cxkk puts in v0
+    fn draw(chip8: &mut Chip8, count: usize) -> Vec<u8> {
+        (0..count).map(|_| {
+            chip8.run_single().unwrap();
+            chip8.run_single().unwrap();
+            chip8.registers()[0]
+        }).collect()
+    }
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let first = draw(&mut machine(7), 50);
+        assert_eq!(draw(&mut machine(7), 50), first);
+        assert_ne!(draw(&mut machine(8), 50), first);
+    }
+
+    #[test]
+    fn replays_a_recording() {
+        let mut chip8 = machine(1);
+        chip8.start_random_recording();
+        let drawn = draw(&mut chip8, 20);
+        assert_eq!(chip8.stop_random_recording(), drawn);
+
+        // Then carries on from its own seed once the recording runs out
+        let mut replayed = machine(99);
+        replayed.replay_random(drawn.clone());
+        assert_eq!(draw(&mut replayed, 20), drawn);
+        assert_eq!(draw(&mut replayed, 10), draw(&mut machine(99), 10));
+    }
+
+    #[test]
+    fn load_state_restores_the_generator() {
+        let mut chip8 = machine(5);
+        draw(&mut chip8, 10);
+        let saved = chip8.save_state();
+        let after = draw(&mut chip8, 10);
+
+        let mut loaded = machine(6);
+        draw(&mut loaded, 3);
+        loaded.load_state(&saved).unwrap();
+        assert_eq!(draw(&mut loaded, 10), after);
+    }
+
+    #[test]
+    fn restore_jumps_straight_to_the_position() {
+        // Far enough in that stepping there a draw at a time would hang
+        let mut random = Random::new(3);
+        random.restore(3, 1 << 40, 1 << 42);
+        let mut other = Random::new(4);
+        other.restore(3, 1 << 40, random.position());
+        let bytes: Vec<u8> = (0..16).map(|_| random.next_byte()).collect();
+        assert_eq!((0..16).map(|_| other.next_byte()).collect::<Vec<u8>>(), bytes);
+        assert_eq!((random.seed(), random.draws()), (3, (1 << 40) + 16));
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use crate::emulator::Chip8;
+use crate::state::Snapshot;
+
+// What it takes to step one snapshot back from the next newer one. Memory
+// and the framebuffer are stored as run length encoded XOR deltas, since
+// only a few bytes change between frames.
+struct Delta {
+    state: Snapshot,
+    memory: Vec<u8>,
+    display: Vec<u8>,
+}
+
+// Ring buffer of past states for stepping emulation backwards. Call record
+// once per frame; every `interval` frames a snapshot is kept, up to
+// `capacity` of them, after which the oldest are dropped.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    frames_since: u32,
+    // Newest snapshot, kept whole. Everything older is a delta against
+    // the one after it.
+    latest: Option<Snapshot>,
+    history: VecDeque<Delta>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize, interval: u32) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            capacity,
+            frames_since: 0,
+            latest: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    // How many frames back rewind can currently go
+    pub fn available_frames(&self) -> usize {
+        (self.history.len() + self.latest.is_some() as usize) * self.interval as usize
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+        self.frames_since = 0;
+    }
+
+    pub fn record(&mut self, chip8: &Chip8) {
+        self.frames_since += 1;
+        if self.latest.is_some() && self.frames_since < self.interval {
+            return;
+        }
+        self.frames_since = 0;
+
+        let snapshot = chip8.snapshot();
+        if let Some(previous) = self.latest.take() {
+            self.history.push_back(Delta {
+                memory: diff(&snapshot.memory, &previous.memory),
+                display: diff(&snapshot.display, &previous.display),
+                state: Snapshot { memory: vec![], display: vec![], ..previous },
+            });
+
+            if self.history.len() > self.capacity {
+                self.history.pop_front();
+            }
+        }
+        self.latest = Some(snapshot);
+    }
+
+    // Put the emulator back `frames` frames (rounded up to the recording
+    // interval). Returns false if there was nothing to rewind to. If there
+    // isn't enough history it goes back as far as it can.
+    pub fn rewind(&mut self, chip8: &mut Chip8, frames: usize) -> bool {
+        let mut current = match self.latest.take() {
+            Some(latest) => latest,
+            None => return false,
+        };
+
+        // The newest snapshot is frames_since frames old, each one before
+        // it another interval older
+        let frames_since = self.frames_since as usize;
+        let steps = if frames > frames_since {
+            (frames - frames_since).div_ceil(self.interval as usize)
+        } else {0};
+
+        for _ in 0..steps {
+            let delta = match self.history.pop_back() {
+                Some(delta) => delta,
+                None => break,
+            };
+            current = Snapshot {
+                memory: apply(&current.memory, &delta.memory),
+                display: apply(&current.display, &delta.display),
+                ..delta.state
+            };
+        }
+
+        chip8.restore(current.clone());
+        self.latest = Some(current);
+        self.frames_since = 0;
+        true
+    }
+}
+
+// Encode the XOR of `from` and `to` as runs of (unchanged count, changed
+// count, changed bytes), counts being u16 little endian. If the lengths differ
+// (resolution switched) the whole of `to` is stored after a 0xFF marker.
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = vec![];
+
+    if from.len() != to.len() {
+        out.push(0xFF);
+        out.extend_from_slice(to);
+        return out;
+    }
+    out.push(0);
+
+    let mut pos = 0;
+    while pos < to.len() {
+        let start = pos;
+        while pos < to.len() && pos - start < u16::MAX as usize && from[pos] == to[pos] {
+            pos += 1;
+        }
+        let same = pos - start;
+
+        let start = pos;
+        while pos < to.len() && pos - start < u16::MAX as usize && from[pos] != to[pos] {
+            pos += 1;
+        }
+
+        out.extend_from_slice(&(same as u16).to_le_bytes());
+        out.extend_from_slice(&((pos - start) as u16).to_le_bytes());
+        for i in start..pos {
+            out.push(from[i] ^ to[i]);
+        }
+    }
+
+    out
+}
+
+fn apply(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    if delta[0] == 0xFF {
+        return delta[1..].to_vec();
+    }
+
+    let mut out = from.to_vec();
+    let (mut pos, mut read) = (0, 1);
+    while read < delta.len() {
+        let same = u16::from_le_bytes([delta[read], delta[read + 1]]) as usize;
+        let changed = u16::from_le_bytes([delta[read + 2], delta[read + 3]]) as usize;
+        read += 4;
+        pos += same;
+
+        for i in 0..changed {
+            out[pos + i] ^= delta[read + i];
+        }
+        pos += changed;
+        read += changed;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    // Counts up in v0, stores it at 0x300 and draws it, so memory and the
+    // screen change every frame. SUPER-CHIP builds switch to hires once v0
+    // reaches 5.
+    fn counter(platform: Platform) -> Chip8 {
+        let mut chip8 = Chip8::for_platform(platform);
+        chip8.set_cycles_per_second(300);
+        chip8.load_rom_data(vec![
+            0x70, 0x01, // add v0, 1
+            0x40, 0x05, // sne v0, 5
+            0x00, 0xFF, // high
+            0xA3, 0x00, // ld i, 0x300
+            0xF0, 0x55, // ld [i], v0
+            0xD0, 0x15, // drw v0, v1, 5
+            0x12, 0x00, // jp 0x200
+        ]).unwrap();
+        chip8
+    }
+
+    // Run `frames` frames, recording each one, and return the state after each
+    fn record(chip8: &mut Chip8, rewind: &mut Rewind, frames: usize) -> Vec<Vec<u8>> {
+        let mut states = vec![];
+        for _ in 0..frames {
+            chip8.run_frame().unwrap();
+            rewind.record(chip8);
+            states.push(chip8.save_state());
+        }
+        states
+    }
+
+    #[test]
+    fn rewinds_to_the_recorded_frame() {
+        let mut chip8 = counter(Platform::Chip8);
+        let mut rewind = Rewind::new(100, 1);
+        let states = record(&mut chip8, &mut rewind, 20);
+
+        assert!(rewind.rewind(&mut chip8, 3));
+        assert!(chip8.save_state() == states[16]);
+        assert!(rewind.rewind(&mut chip8, 10));
+        assert!(chip8.save_state() == states[6]);
+        assert!(rewind.rewind(&mut chip8, 0));
+        assert!(chip8.save_state() == states[6]);
+
+        // Recording again carries on from the rewound frame
+        record(&mut chip8, &mut rewind, 2);
+        assert!(rewind.rewind(&mut chip8, 2));
+        assert!(chip8.save_state() == states[6]);
+        assert_eq!(rewind.available_frames(), 7);
+    }
+
+    #[test]
+    fn rounds_up_to_the_interval() {
+        let mut chip8 = counter(Platform::Chip8);
+        let mut rewind = Rewind::new(100, 3);
+        // Frames 0, 3, 6 and 9 are kept, frame 11 is the current one
+        let states = record(&mut chip8, &mut rewind, 12);
+        assert_eq!(rewind.available_frames(), 12);
+
+        assert!(rewind.rewind(&mut chip8, 2));
+        assert!(chip8.save_state() == states[9]);
+        assert!(rewind.rewind(&mut chip8, 4));
+        assert!(chip8.save_state() == states[3]);
+    }
+
+    #[test]
+    fn rewinds_across_a_resolution_switch() {
+        let mut chip8 = counter(Platform::SuperChip);
+        let mut rewind = Rewind::new(100, 1);
+        let states = record(&mut chip8, &mut rewind, 20);
+        assert_eq!(chip8.width(), 128);
+
+        assert!(rewind.rewind(&mut chip8, 19));
+        assert!(chip8.save_state() == states[0]);
+        assert_eq!(chip8.width(), 64);
+    }
+
+    #[test]
+    fn drops_history_beyond_capacity() {
+        let mut chip8 = counter(Platform::Chip8);
+        let mut rewind = Rewind::new(5, 1);
+        let states = record(&mut chip8, &mut rewind, 20);
+        assert_eq!(rewind.available_frames(), 6);
+
+        // Goes back as far as it can
+        assert!(rewind.rewind(&mut chip8, 100));
+        assert!(chip8.save_state() == states[14]);
+        assert!(rewind.rewind(&mut chip8, 1));
+        assert!(chip8.save_state() == states[14]);
+    }
+
+    #[test]
+    fn nothing_to_rewind_after_clear() {
+        let mut chip8 = counter(Platform::Chip8);
+        let mut rewind = Rewind::new(5, 1);
+        record(&mut chip8, &mut rewind, 3);
+        rewind.clear();
+        assert!(!rewind.rewind(&mut chip8, 1));
+    }
+
+    #[test]
+    fn diff_and_apply_roundtrip() {
+        // Runs longer than a u16 count, as in XO-CHIP memory
+        let from = vec![0; 70000];
+        let mut to = from.clone();
+        to[3] = 1;
+        to[69999] = 2;
+        for byte in &mut to[100..66000] {
+            *byte = 7;
+        }
+        assert_eq!(apply(&from, &diff(&from, &to)), to);
+        assert_eq!(apply(&to, &diff(&to, &from)), from);
+
+        // Whole buffer when the size changes
+        let small = vec![1; 10];
+        let delta = diff(&from, &small);
+        assert_eq!(delta[0], 0xFF);
+        assert_eq!(apply(&from, &delta), small);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::error::Chip8Error;
+use crate::quirks::Platform;
+
+// Everything needed to put a Chip8 back exactly where it was. Configuration
+// (quirks, cycle rate, backends) is not part of the state.
+#[derive(Clone, PartialEq)]
+pub struct Snapshot {
+    pub platform: Platform,
+    pub memory: Vec<u8>,
+    pub registers: [u8; 16],
+    pub stack: [u16; 16],
+    pub sp: u8,
+    pub pc: u16,
+    pub i: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub display: Vec<u8>,
+    pub hires: bool,
+    pub planes: u8,
+    pub pattern: Option<[u8; 16]>,
+    pub pitch: u8,
+    pub halted: bool,
+    pub rpl: [u8; 16],
+    pub waiting_for_key: bool,
+    // Cxkk generator position, see Random
+    pub rng_seed: u64,
+    pub rng_draws: u64,
+    pub rng_position: u64,
+}
+
+const MAGIC: &[u8; 4] = b"C8SS";
+
+// Bump when the layout below changes. Old versions are rejected.
+const VERSION: u8 = 3;
+
+impl Snapshot {
+    // Layout, all little endian:
+    //   "C8SS", version, platform, pc, i, sp, dt, st, registers[16],
+    //   stack[16], hires, halted, waiting_for_key, planes, pitch,
+    //   has pattern, pattern[16], rpl[16], memory length (u32), memory,
+    //   display length (u32), display, rng seed (u64), rng draws (u64),
+    //   rng position (u64)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = vec![];
+
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(match self.platform {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2,
+        });
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.i.to_le_bytes());
+        out.push(self.sp);
+        out.push(self.dt);
+        out.push(self.st);
+        out.extend_from_slice(&self.registers);
+        for entry in &self.stack {
+            out.extend_from_slice(&entry.to_le_bytes());
+        }
+        out.push(self.hires as u8);
+        out.push(self.halted as u8);
+        out.push(self.waiting_for_key as u8);
+        out.push(self.planes);
+        out.push(self.pitch);
+        out.push(self.pattern.is_some() as u8);
+        out.extend_from_slice(&self.pattern.unwrap_or([0; 16]));
+        out.extend_from_slice(&self.rpl);
+        out.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&(self.display.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.display);
+        out.extend_from_slice(&self.rng_seed.to_le_bytes());
+        out.extend_from_slice(&self.rng_draws.to_le_bytes());
+        out.extend_from_slice(&self.rng_position.to_le_bytes());
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Snapshot, Chip8Error> {
+        let mut reader = Reader { data, pos: 0 };
+
+        if reader.bytes(4)? != MAGIC {
+            return Err(Chip8Error::BadSaveState("not a save state"));
+        }
+        if reader.u8()? != VERSION {
+            return Err(Chip8Error::BadSaveState("unsupported save state version"));
+        }
+
+        let platform = match reader.u8()? {
+            0 => Platform::Chip8,
+            1 => Platform::SuperChip,
+            2 => Platform::XoChip,
+            _ => return Err(Chip8Error::BadSaveState("unknown platform")),
+        };
+
+        let pc = reader.u16()?;
+        let i = reader.u16()?;
+        let sp = reader.u8()?;
+        let dt = reader.u8()?;
+        let st = reader.u8()?;
+        let mut registers = [0; 16];
+        registers.copy_from_slice(reader.bytes(16)?);
+        let mut stack = [0; 16];
+        for entry in stack.iter_mut() {
+            *entry = reader.u16()?;
+        }
+        let hires = reader.u8()? != 0;
+        let halted = reader.u8()? != 0;
+        let waiting_for_key = reader.u8()? != 0;
+        let planes = reader.u8()?;
+        let pitch = reader.u8()?;
+        let has_pattern = reader.u8()? != 0;
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(reader.bytes(16)?);
+        let mut rpl = [0; 16];
+        rpl.copy_from_slice(reader.bytes(16)?);
+        let memory_len = reader.u32()? as usize;
+        let memory = reader.bytes(memory_len)?.to_vec();
+        let display_len = reader.u32()? as usize;
+        let display = reader.bytes(display_len)?.to_vec();
+        let rng_seed = reader.u64()?;
+        let rng_draws = reader.u64()?;
+        let rng_position = reader.u64()?;
+
+        if memory.len() != platform.memory_size() {
+            return Err(Chip8Error::BadSaveState("memory size does not match platform"));
+        }
+        if sp as usize > stack.len() {
+            return Err(Chip8Error::BadSaveState("stack pointer out of range"));
+        }
+
+        Ok(Snapshot {
+            platform,
+            memory,
+            registers,
+            stack,
+            sp,
+            pc,
+            i,
+            dt,
+            st,
+            display,
+            hires,
+            planes,
+            pattern: if has_pattern {Some(pattern)} else {None},
+            pitch,
+            halted,
+            rpl,
+            waiting_for_key,
+            rng_seed,
+            rng_draws,
+            rng_position,
+        })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.pos + count > self.data.len() {
+            return Err(Chip8Error::BadSaveState("save state is truncated"));
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Chip8Error> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Chip8Error> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Chip8Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Beeper;
+    use crate::emulator::Chip8;
+    use std::{rc::Rc, cell::RefCell};
+
+    // A few platform specific instructions, then a subroutine that sets the
+    // timers and draws random digits forever
+    fn game(platform: Platform) -> Vec<u8> {
+        let mut rom = match platform {
+            // ld v2, 1; ld v3, 2; ld v4, 3; ld v5, 4
+            Platform::Chip8 => vec![0x62, 0x01, 0x63, 0x02, 0x64, 0x03, 0x65, 0x04],
+            // high; ld v3, 2; ld r, v3; ld v5, 4
+            Platform::SuperChip => vec![0x00, 0xFF, 0x63, 0x02, 0xF3, 0x75, 0x65, 0x04],
+            // high; plane 3; audio; pitch v5
+            Platform::XoChip => vec![0x00, 0xFF, 0xF3, 0x01, 0xF0, 0x02, 0xF5, 0x3A],
+        };
+        rom.extend_from_slice(&[
+            0x22, 0x0C, // call 0x20c
+            0x00, 0x00,
+            0x6A, 0x0F, // ld va, 15
+            0xFA, 0x15, // ld dt, va
+            0xFA, 0x18, // ld st, va
+            0xC1, 0xFF, // rnd v1, 0xff
+            0xF1, 0x29, // ld f, v1
+            0xD0, 0x15, // drw v0, v1, 5
+            0x70, 0x01, // add v0, 1
+            0x12, 0x12, // jp 0x212
+        ]);
+        rom
+    }
+
+    fn start(platform: Platform, seed: u64) -> Chip8 {
+        let mut chip8 = Chip8::for_platform(platform);
+        chip8.set_cycles_per_second(600);
+        chip8.set_seed(seed);
+        chip8.load_rom_data(game(platform)).unwrap();
+        chip8
+    }
+
+    fn run_frames(chip8: &mut Chip8, frames: usize) {
+        for _ in 0..frames {
+            chip8.run_frame().unwrap();
+        }
+    }
+
+    // Save state of a game 5 frames in
+    fn mid_game(platform: Platform) -> Vec<u8> {
+        let mut chip8 = start(platform, 1);
+        run_frames(&mut chip8, 5);
+        chip8.save_state()
+    }
+
+    #[test]
+    fn save_and_load_every_platform() {
+        for platform in [Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            let mut chip8 = start(platform, 1);
+            run_frames(&mut chip8, 5);
+            let saved = chip8.save_state();
+            assert_eq!((chip8.sp(), chip8.delay_timer()), (1, 10));
+            run_frames(&mut chip8, 5);
+            let later = chip8.save_state();
+
+            // A fresh machine with another seed picks up exactly where the
+            // save left off, random numbers included
+            let mut loaded = start(platform, 2);
+            loaded.load_state(&saved).unwrap();
+            assert!(loaded.save_state() == saved, "{:?}", platform);
+            run_frames(&mut loaded, 5);
+            assert!(loaded.save_state() == later, "{:?}", platform);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut chip8 = start(Platform::Chip8, 1);
+
+        let mut saved = mid_game(Platform::Chip8);
+        saved[0] = b'X';
+        assert!(matches!(chip8.load_state(&saved), Err(Chip8Error::BadSaveState("not a save state"))));
+
+        let mut saved = mid_game(Platform::Chip8);
+        saved[4] = VERSION + 1;
+        assert!(matches!(chip8.load_state(&saved), Err(Chip8Error::BadSaveState("unsupported save state version"))));
+
+        let saved = mid_game(Platform::Chip8);
+        for len in [0, 10, saved.len() - 1] {
+            assert!(matches!(chip8.load_state(&saved[..len]), Err(Chip8Error::BadSaveState("save state is truncated"))));
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_states() {
+        let mut chip8 = start(Platform::Chip8, 1);
+        let snapshot = Snapshot::decode(&mid_game(Platform::Chip8)).unwrap();
+
+        let bad = Snapshot { sp: 17, ..snapshot.clone() };
+        assert!(matches!(chip8.load_state(&bad.encode()), Err(Chip8Error::BadSaveState("stack pointer out of range"))));
+
+        let bad = Snapshot { hires: true, ..snapshot.clone() };
+        assert!(matches!(chip8.load_state(&bad.encode()), Err(Chip8Error::BadSaveState("display size does not match resolution"))));
+
+        let bad = Snapshot { platform: Platform::XoChip, ..snapshot };
+        assert!(matches!(chip8.load_state(&bad.encode()), Err(Chip8Error::BadSaveState("memory size does not match platform"))));
+    }
+
+    // Keeps every pattern the emulator hands it
+    struct PatternLog(Rc<RefCell<Vec<Option<[u8; 16]>>>>);
+
+    impl Beeper for PatternLog {
+        fn set_beeping(&mut self, _beeping: bool) {}
+
+        fn set_pattern(&mut self, pattern: Option<[u8; 16]>, _rate: f32) {
+            self.0.borrow_mut().push(pattern);
+        }
+    }
+
+    #[test]
+    fn loading_a_save_without_a_pattern_clears_it() {
+        let patterns = Rc::new(RefCell::new(vec![]));
+        let mut chip8 = start(Platform::XoChip, 1);
+        chip8.set_beeper(Box::new(PatternLog(patterns.clone())));
+        run_frames(&mut chip8, 1);
+        assert!(patterns.borrow().last().unwrap().is_some());
+
+        let mut snapshot = chip8.snapshot();
+        snapshot.pattern = None;
+        chip8.load_state(&snapshot.encode()).unwrap();
+        assert_eq!(*patterns.borrow().last().unwrap(), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{fs::File, io::{self, Write, BufWriter}};
+
+use crate::disassembler::mnemonic;
+use crate::emulator::Chip8;
+
+// Line layout for traces. Templates can use {cycle}, {pc}, {op},
+// {mnemonic}, {v0} - {vf}, {i}, {sp}, {dt} and {st}; everything but
+// {cycle} and {mnemonic} is uppercase hex.
+#[derive(Clone)]
+pub enum TraceFormat {
+    // Cycle count, decoded instruction and all registers
+    Default,
+    // PC:0200 OP:6000 V0:00 ... VF:00 I:0000 SP:0 DT:00 ST:00, the line
+    // format used by most other CHIP-8 emulators' trace logs
+    Compact,
+    Template(String),
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "default" => Some(TraceFormat::Default),
+            "compact" => Some(TraceFormat::Compact),
+            _ => None,
+        }
+    }
+
+    fn template(&self) -> String {
+        let registers: Vec<String> = (0..16).map(|r| format!("V{:X}:{{v{:x}}}", r, r)).collect();
+        match self {
+            TraceFormat::Default => format!("{{cycle}} {{pc}}: {{op}} {{mnemonic}} | {} I:{{i}} SP:{{sp}} DT:{{dt}} ST:{{st}}", registers.join(" ")),
+            TraceFormat::Compact => format!("PC:{{pc}} OP:{{op}} {} I:{{i}} SP:{{sp}} DT:{{dt}} ST:{{st}}", registers.join(" ")),
+            TraceFormat::Template(template) => template.clone(),
+        }
+    }
+}
+
+// Writes one line per instruction, describing the state right before it runs
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    template: String,
+    // Only the default layout lines its columns up, templates get the
+    // mnemonic as is
+    pad_mnemonic: bool,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        let pad_mnemonic = matches!(format, TraceFormat::Default);
+        Tracer { writer, template: format.template(), pad_mnemonic }
+    }
+
+    pub fn to_file(path: &str, format: TraceFormat) -> io::Result<Tracer> {
+        Ok(Tracer::new(Box::new(BufWriter::new(File::create(path)?)), format))
+    }
+
+    pub fn log(&mut self, chip8: &Chip8, cycle: u64, opcode: u16) {
+        let mut mnemonic = mnemonic(opcode);
+        if self.pad_mnemonic {
+            mnemonic = format!("{:<16}", mnemonic);
+        }
+
+        let mut line = self.template
+            .replace("{cycle}", &cycle.to_string())
+            .replace("{pc}", &format!("{:04X}", chip8.pc()))
+            .replace("{op}", &format!("{:04X}", opcode))
+            .replace("{mnemonic}", &mnemonic)
+            .replace("{i}", &format!("{:04X}", chip8.i()))
+            .replace("{sp}", &format!("{:X}", chip8.sp()))
+            .replace("{dt}", &format!("{:02X}", chip8.delay_timer()))
+            .replace("{st}", &format!("{:02X}", chip8.sound_timer()));
+
+        for (reg, value) in chip8.registers().iter().enumerate() {
+            line = line.replace(&format!("{{v{:x}}}", reg), &format!("{:02X}", value));
+        }
+
+        // A broken trace file shouldn't stop the emulator
+        writeln!(self.writer, "{}", line).ok();
+    }
+
+    pub fn flush(&mut self) {
+        self.writer.flush().ok();
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Keeps what the tracer writes where the test can still read it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The lines traced while running two instructions,
+    // ld v1, 0xab; ld i, 0x123
+    fn trace(format: TraceFormat) -> String {
+        let output = Output::default();
+        let mut chip8 = Chip8::new();
+        chip8.load_rom_data(vec![0x61, 0xAB, 0xA1, 0x23]).unwrap();
+        chip8.set_tracer(Some(Tracer::new(Box::new(output.clone()), format)));
+        chip8.run_single().unwrap();
+        chip8.run_single().unwrap();
+        chip8.set_tracer(None);
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        text
+    }
+
+    #[test]
+    fn compact_lines() {
+        assert_eq!(trace(TraceFormat::Compact), "\
+PC:0200 OP:61AB V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 V8:00 V9:00 VA:00 VB:00 VC:00 VD:00 VE:00 VF:00 I:0000 SP:0 DT:00 ST:00
+PC:0202 OP:A123 V0:00 V1:AB V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 V8:00 V9:00 VA:00 VB:00 VC:00 VD:00 VE:00 VF:00 I:0000 SP:0 DT:00 ST:00
+");
+    }
+
+    #[test]
+    fn only_the_default_layout_pads_mnemonics() {
+        let default = trace(TraceFormat::Default);
+        let first = default.lines().next().unwrap();
+        assert!(first.starts_with(&format!("0 0200: 61AB {:<16} | V0:00", mnemonic(0x61AB))), "{}", first);
+
+        let template = trace(TraceFormat::Template("{pc} {mnemonic}|".to_string()));
+        assert_eq!(template, format!("0200 {}|\n0202 {}|\n", mnemonic(0x61AB), mnemonic(0xA123)));
+    }
+}
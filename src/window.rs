@@ -0,0 +1,178 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell, fs};
+use rgraphics::{textures::RenderTexture2D, Program, colors, input::Key};
+
+use crate::emulator::{Chip8, HIRES_WIDTH, HIRES_HEIGHT};
+use crate::backend::{Display, SharedKeypad};
+use crate::error::Chip8Error;
+use crate::rewind::Rewind;
+
+// Display sink that copies frames into the window's texture. The texture is
+// always hires sized, low resolution frames are scaled up to fill it.
+struct TextureDisplay {
+    texture: Rc<RefCell<RenderTexture2D>>,
+}
+
+impl Display for TextureDisplay {
+    fn draw(&mut self, frame: &[u8], width: usize, _height: usize) {
+        let mut texture = self.texture.borrow_mut();
+        let scale = HIRES_WIDTH / width;
+        for y in 0..HIRES_HEIGHT {
+            for x in 0..HIRES_WIDTH {
+                let color = match frame[(y / scale) * width + x / scale] {
+                    0 => colors::BLACK,
+                    1 => colors::WHITE,
+                    // Second XO-CHIP plane, and both planes overlapping
+                    2 => colors::Color::new(0.33, 0.33, 0.33, 1.0),
+                    _ => colors::Color::new(0.67, 0.67, 0.67, 1.0),
+                };
+                texture.set_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+}
+
+// rgraphics frontend for the emulator core. Owns the texture the framebuffer
+// is copied to and maps keyboard keys to the hex keypad.
+pub struct Window {
+    display: Rc<RefCell<RenderTexture2D>>,
+    keypad: SharedKeypad,
+    key_map: HashMap<u8, Key>,
+
+    // Set when the emulator hits an error. Emulation stops but the last
+    // frame stays on screen.
+    crashed: Option<Chip8Error>,
+
+    // Save states go to `<save_path>.s<slot>`. F1 - F4 save to slots 1 - 4,
+    // F5 - F8 load them back.
+    save_path: String,
+    save_keys: [Key; 4],
+    load_keys: [Key; 4],
+    // Hotkeys held down last frame, so holding one only fires once
+    held: Vec<Key>,
+
+    // Holding backspace steps back through the last ten seconds or so
+    rewind: Rewind,
+
+    // Fraction of the window the screen fills, centered
+    scale: f32,
+}
+
+impl Window {
+    pub fn new(program: &Program) -> Window {
+        let mut key_map: HashMap<u8, Key> = HashMap::new();
+
+        key_map.insert(0, Key::Key0);
+        key_map.insert(1, Key::Key1);
+        key_map.insert(2, Key::Key2);
+        key_map.insert(3, Key::Key3);
+        key_map.insert(4, Key::Key4);
+        key_map.insert(5, Key::Key5);
+        key_map.insert(6, Key::Key6);
+        key_map.insert(7, Key::Key7);
+        key_map.insert(8, Key::Key8);
+        key_map.insert(9, Key::Key9);
+        key_map.insert(10, Key::A);
+        key_map.insert(11, Key::B);
+        key_map.insert(12, Key::C);
+        key_map.insert(13, Key::D);
+        key_map.insert(14, Key::E);
+        key_map.insert(15, Key::F);
+
+        Window {
+            display: Rc::new(RefCell::new(RenderTexture2D::new(&program.renderer, HIRES_WIDTH as u32, HIRES_HEIGHT as u32))),
+            keypad: SharedKeypad::new(),
+            key_map,
+            crashed: None,
+            save_path: "chip8".to_string(),
+            save_keys: [Key::F1, Key::F2, Key::F3, Key::F4],
+            load_keys: [Key::F5, Key::F6, Key::F7, Key::F8],
+            held: vec![],
+            rewind: Rewind::new(300, 2),
+            scale: 1.0,
+        }
+    }
+
+    // Hook the emulator's display and keypad up to this window
+    pub fn attach(&self, chip8: &mut Chip8) {
+        chip8.set_display(Box::new(TextureDisplay { texture: self.display.clone() }));
+        chip8.set_keypad(Box::new(self.keypad.clone()));
+    }
+
+    // Usually the rom path, so each game gets its own slots
+    pub fn set_save_path(&mut self, path: &str) {
+        self.save_path = path.to_string();
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale.clamp(0.0, 1.0);
+    }
+
+    fn slot_path(&self, slot: usize) -> String {
+        format!("{}.s{}", self.save_path, slot + 1)
+    }
+
+    // True on the frame `key` goes down
+    fn pressed(&mut self, key: Key, program: &Program) -> bool {
+        let down = program.input_manager.is_key_down(key);
+        let was_down = self.held.contains(&key);
+        if down && !was_down {
+            self.held.push(key);
+        } else if !down && was_down {
+            self.held.retain(|held| *held != key);
+        }
+        down && !was_down
+    }
+
+    fn handle_save_keys(&mut self, chip8: &mut Chip8, program: &Program) {
+        for slot in 0..4 {
+            if self.pressed(self.save_keys[slot], program) {
+                match fs::write(self.slot_path(slot), chip8.save_state()) {
+                    Ok(()) => println!("Saved state to slot {}", slot + 1),
+                    Err(err) => eprintln!("Unable to save slot {}: {}", slot + 1, err),
+                }
+            }
+
+            if self.pressed(self.load_keys[slot], program) {
+                let loaded = fs::read(self.slot_path(slot))
+                    .map_err(Chip8Error::from)
+                    .and_then(|data| chip8.load_state(&data));
+                match loaded {
+                    Ok(()) => {
+                        println!("Loaded state from slot {}", slot + 1);
+                        self.crashed = None;
+                        self.rewind.clear();
+                    },
+                    Err(err) => eprintln!("Unable to load slot {}: {}", slot + 1, err),
+                }
+            }
+        }
+    }
+
+    pub fn update(&mut self, chip8: &mut Chip8, program: &mut Program) {
+        for (key, mapped) in &self.key_map {
+            self.keypad.set_key(*key, program.input_manager.is_key_down(*mapped));
+        }
+
+        self.handle_save_keys(chip8, program);
+
+        if program.input_manager.is_key_down(Key::Back) {
+            if self.rewind.rewind(chip8, 2) {
+                self.crashed = None;
+            }
+            chip8.reset_clock();
+        } else if self.crashed.is_none() {
+            if let Err(err) = chip8.clock() {
+                eprintln!("Emulation stopped: {}", err);
+                self.crashed = Some(err);
+            }
+            self.rewind.record(chip8);
+        }
+        self.draw(program);
+    }
+
+    pub fn draw(&mut self, program: &mut Program) {
+        let mut display = self.display.borrow_mut();
+        display.apply(&program.renderer);
+        program.draw_texture(-self.scale, self.scale, 2.0 * self.scale, 2.0 * self.scale, &display);
+    }
+}